@@ -0,0 +1,213 @@
+use crate::analysis::{FrameStats, GRID_HEIGHT, GRID_WIDTH};
+use chrono::DateTime;
+use serde::{Deserialize, Serialize};
+
+/// How much a block's mean luminance (0-255) must change between frames to
+/// count as changed.
+const BLOCK_CHANGE_THRESHOLD: u8 = 12;
+
+/// A rectangle written as `x,y,w,h`, in fractions of the frame's width and
+/// height, so it doesn't depend on the camera's resolution.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Region {
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+}
+
+impl Region {
+    fn contains(&self, x: f32, y: f32) -> bool {
+        x >= self.x && x < self.x + self.width && y >= self.y && y < self.y + self.height
+    }
+}
+
+/// One or more regions of interest separated by `;`. Only changes inside
+/// them count as activity.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RegionsOfInterest(pub Vec<Region>);
+
+impl RegionsOfInterest {
+    fn parse(s: &str) -> Result<Self, String> {
+        s.split(';')
+            .map(|region| {
+                let values: Vec<f32> = region
+                    .split(',')
+                    .map(|v| v.trim().parse())
+                    .collect::<Result<_, _>>()
+                    .map_err(|_| format!("invalid region: {}", region))?;
+                match values[..] {
+                    [x, y, width, height] => Ok(Region {
+                        x,
+                        y,
+                        width,
+                        height,
+                    }),
+                    _ => Err(format!("region must be x,y,w,h: {}", region)),
+                }
+            })
+            .collect::<Result<_, _>>()
+            .map(RegionsOfInterest)
+    }
+
+    /// Which blocks of the analysis grid fall inside a region.
+    fn mask(&self) -> [bool; GRID_WIDTH * GRID_HEIGHT] {
+        let mut mask = [false; GRID_WIDTH * GRID_HEIGHT];
+        for (i, included) in mask.iter_mut().enumerate() {
+            let x = ((i % GRID_WIDTH) as f32 + 0.5) / GRID_WIDTH as f32;
+            let y = ((i / GRID_WIDTH) as f32 + 0.5) / GRID_HEIGHT as f32;
+            *included = self.0.iter().any(|region| region.contains(x, y));
+        }
+        mask
+    }
+}
+
+impl<'de> Deserialize<'de> for RegionsOfInterest {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let s: String = Deserialize::deserialize(deserializer)?;
+        RegionsOfInterest::parse(&s).map_err(serde::de::Error::custom)
+    }
+}
+
+/// A stretch of time with significant change between consecutive frames.
+#[derive(Debug, Clone, Serialize)]
+pub struct ActivityInterval {
+    pub start: String,
+    pub end: String,
+    /// Highest change score in the interval, the fraction of blocks that changed
+    pub peak_score: f32,
+    pub mean_score: f32,
+    pub frames: usize,
+    /// Timestamps of the first and last active frames
+    pub first_frame: i64,
+    pub last_frame: i64,
+    /// Timestamp of the frame with the peak score
+    pub representative_frame: i64,
+}
+
+/// Scores each frame by the fraction of blocks inside `roi` that changed
+/// since the previous frame. The first frame, and frames next to one that
+/// couldn't be analyzed, score zero.
+pub fn change_scores(stats: &[Option<FrameStats>], roi: Option<&RegionsOfInterest>) -> Vec<f32> {
+    let mask = roi.map_or([true; GRID_WIDTH * GRID_HEIGHT], |roi| roi.mask());
+    let blocks = mask.iter().filter(|&&included| included).count().max(1) as f32;
+
+    let mut scores = vec![0.0; stats.len()];
+    for i in 1..stats.len() {
+        if let (Some(previous), Some(current)) = (&stats[i - 1], &stats[i]) {
+            let changed = previous
+                .grid
+                .iter()
+                .zip(current.grid.iter())
+                .zip(mask.iter())
+                .filter(|((a, b), &included)| included && a.abs_diff(**b) > BLOCK_CHANGE_THRESHOLD)
+                .count();
+            scores[i] = changed as f32 / blocks;
+        }
+    }
+    scores
+}
+
+/// Groups frames scoring at least `threshold` into intervals, merging
+/// active frames less than `max_gap` seconds apart.
+pub fn intervals(
+    timestamps: &[i64],
+    scores: &[f32],
+    threshold: f32,
+    max_gap: i64,
+) -> Vec<ActivityInterval> {
+    let mut groups: Vec<Vec<usize>> = Vec::new();
+    for (i, &score) in scores.iter().enumerate() {
+        if score < threshold {
+            continue;
+        }
+        match groups.last_mut() {
+            Some(group) if timestamps[i] - timestamps[*group.last().unwrap()] <= max_gap => {
+                group.push(i)
+            }
+            _ => groups.push(vec![i]),
+        }
+    }
+
+    groups
+        .into_iter()
+        .map(|group| {
+            let peak = *group
+                .iter()
+                .max_by(|&&a, &&b| scores[a].total_cmp(&scores[b]))
+                .unwrap();
+            let total: f32 = group.iter().map(|&i| scores[i]).sum();
+            ActivityInterval {
+                start: format_timestamp(timestamps[group[0]]),
+                end: format_timestamp(timestamps[*group.last().unwrap()]),
+                peak_score: scores[peak],
+                mean_score: total / group.len() as f32,
+                frames: group.len(),
+                first_frame: timestamps[group[0]],
+                last_frame: timestamps[*group.last().unwrap()],
+                representative_frame: timestamps[peak],
+            }
+        })
+        .collect()
+}
+
+fn format_timestamp(timestamp: i64) -> String {
+    DateTime::from_timestamp(timestamp, 0)
+        .map(|time| time.to_rfc3339())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stats_with_grid(grid: [u8; GRID_WIDTH * GRID_HEIGHT]) -> Option<FrameStats> {
+        Some(FrameStats {
+            luminance: 0.0,
+            contrast: 0.0,
+            dhash: 0,
+            grid,
+        })
+    }
+
+    #[test]
+    fn test_change_scores_respects_roi() {
+        let still = [100u8; GRID_WIDTH * GRID_HEIGHT];
+        let mut moved = still;
+        // Change the whole top row of blocks
+        moved[..GRID_WIDTH].fill(200);
+
+        let stats = vec![stats_with_grid(still), stats_with_grid(moved), None];
+        let scores = change_scores(&stats, None);
+        assert_eq!(scores, vec![0.0, GRID_WIDTH as f32 / 192.0, 0.0]);
+
+        let top = RegionsOfInterest::parse("0,0,1,0.25").unwrap();
+        assert_eq!(change_scores(&stats, Some(&top))[1], 1.0 / 3.0);
+
+        let bottom = RegionsOfInterest::parse("0,0.5,1,0.5").unwrap();
+        assert_eq!(change_scores(&stats, Some(&bottom))[1], 0.0);
+    }
+
+    #[test]
+    fn test_intervals_merge_nearby_activity() {
+        let timestamps = [0, 60, 120, 180, 240, 900, 960];
+        let scores = [0.0, 0.2, 0.0, 0.4, 0.0, 0.3, 0.01];
+        let intervals = intervals(&timestamps, &scores, 0.05, 300);
+
+        assert_eq!(intervals.len(), 2);
+        assert_eq!(intervals[0].frames, 2);
+        assert_eq!(intervals[0].representative_frame, 180);
+        assert!((intervals[0].mean_score - 0.3).abs() < 1e-6);
+        assert_eq!(intervals[1].start, "1970-01-01T00:15:00+00:00");
+    }
+
+    #[test]
+    fn test_parse_regions() {
+        let roi = RegionsOfInterest::parse("0,0,0.5,0.5;0.5,0.5,0.5,0.5").unwrap();
+        assert_eq!(roi.0.len(), 2);
+        assert!(RegionsOfInterest::parse("0,0,0.5").is_err());
+    }
+}
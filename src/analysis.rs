@@ -0,0 +1,450 @@
+use crate::catalog::Catalog;
+use image::GrayImage;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+/// Frames are measured on a greyscale copy no larger than this on either side.
+const THUMBNAIL_SIZE: u32 = 64;
+
+/// Activity is detected on a coarse grid of blocks rather than single pixels,
+/// which ignores sensor noise and keeps the cached stats small.
+pub const GRID_WIDTH: usize = 16;
+pub const GRID_HEIGHT: usize = 12;
+
+/// Statistics of a frame, measured on a downscaled greyscale copy.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FrameStats {
+    /// Mean luminance, 0-255
+    pub luminance: f32,
+    /// Standard deviation of the luminance. Blank frames are close to zero.
+    pub contrast: f32,
+    /// Perceptual difference hash. Similar frames differ in only a few bits.
+    pub dhash: u64,
+    /// Mean luminance of each block of the frame, row by row.
+    pub grid: [u8; GRID_WIDTH * GRID_HEIGHT],
+}
+
+impl FrameStats {
+    fn measure(thumbnail: &GrayImage) -> Self {
+        let pixels = thumbnail.as_raw();
+        let count = pixels.len().max(1) as f32;
+        let luminance = pixels.iter().map(|&p| p as f32).sum::<f32>() / count;
+        let variance = pixels
+            .iter()
+            .map(|&p| (p as f32 - luminance).powi(2))
+            .sum::<f32>()
+            / count;
+
+        FrameStats {
+            luminance,
+            contrast: variance.sqrt(),
+            dhash: dhash(thumbnail),
+            grid: grid(thumbnail),
+        }
+    }
+
+    /// How many bits of the perceptual hashes of two frames differ.
+    pub fn distance(&self, other: &FrameStats) -> u32 {
+        (self.dhash ^ other.dhash).count_ones()
+    }
+}
+
+fn grid(thumbnail: &GrayImage) -> [u8; GRID_WIDTH * GRID_HEIGHT] {
+    let blocks = image::imageops::resize(
+        thumbnail,
+        GRID_WIDTH as u32,
+        GRID_HEIGHT as u32,
+        image::imageops::FilterType::Triangle,
+    );
+    let mut grid = [0u8; GRID_WIDTH * GRID_HEIGHT];
+    grid.copy_from_slice(blocks.as_raw());
+    grid
+}
+
+/// Difference hash: one bit per pixel of a 9x8 copy of the frame, set when
+/// the pixel is darker than its right hand neighbour.
+fn dhash(thumbnail: &GrayImage) -> u64 {
+    let small = image::imageops::resize(thumbnail, 9, 8, image::imageops::FilterType::Triangle);
+    let mut hash = 0u64;
+    for y in 0..8 {
+        for x in 0..8 {
+            hash <<= 1;
+            if small.get_pixel(x, y)[0] < small.get_pixel(x + 1, y)[0] {
+                hash |= 1;
+            }
+        }
+    }
+    hash
+}
+
+/// Per-frame analysis results, kept for the lifetime of the process so that
+/// repeated renders over the same frames don't decode them again. Frames are
+/// written once and never change, so entries are keyed by path alone.
+///
+/// With a catalog, results are also persisted there so they survive restarts.
+pub struct AnalysisCache {
+    stats: HashMap<PathBuf, Option<FrameStats>>,
+    /// Frames that passed validation. Failures aren't remembered, since a
+    /// frame that is still being written will usually be fine later.
+    valid: HashSet<PathBuf>,
+    catalog: Option<Arc<Catalog>>,
+}
+
+impl AnalysisCache {
+    pub fn new() -> Self {
+        AnalysisCache {
+            stats: HashMap::new(),
+            valid: HashSet::new(),
+            catalog: None,
+        }
+    }
+
+    pub fn with_catalog(catalog: Option<Arc<Catalog>>) -> Self {
+        AnalysisCache {
+            catalog,
+            ..AnalysisCache::new()
+        }
+    }
+}
+
+/// Returns whether each of `paths` is a complete frame: non-empty, last
+/// modified at least `min_age` ago, a JPEG, PNG or WebP image that isn't
+/// truncated, and with a readable header.
+pub fn validate_frames(
+    cache: &Mutex<AnalysisCache>,
+    paths: &[&Path],
+    min_age: Duration,
+) -> Vec<bool> {
+    let (mut unchecked, catalog): (Vec<&Path>, _) = {
+        let cache = cache.lock().unwrap();
+        let unchecked = paths
+            .iter()
+            .filter(|path| !cache.valid.contains(**path))
+            .copied()
+            .collect();
+        (unchecked, cache.catalog.clone())
+    };
+
+    if let Some(catalog) = catalog.as_ref().filter(|_| !unchecked.is_empty()) {
+        match catalog.validated(&unchecked) {
+            Ok(valid) => {
+                unchecked.retain(|path| !valid.contains(*path));
+                cache.lock().unwrap().valid.extend(valid);
+            }
+            Err(e) => eprintln!("Failed to read frame validity from catalog: {}", e),
+        }
+    }
+
+    if !unchecked.is_empty() {
+        let results = in_parallel(&unchecked, |path| match check_frame(path, min_age) {
+            Ok(dimensions) => Some(dimensions),
+            Err(reason) => {
+                eprintln!("Skipping frame {}: {}", path.display(), reason);
+                None
+            }
+        });
+        let checked: Vec<(&Path, (u32, u32))> = unchecked
+            .into_iter()
+            .zip(results)
+            .filter_map(|(path, dimensions)| Some((path, dimensions?)))
+            .collect();
+        if let Some(catalog) = &catalog {
+            if let Err(e) = catalog.put_dimensions(&checked) {
+                eprintln!("Failed to write frame validity to catalog: {}", e);
+            }
+        }
+        let mut cache = cache.lock().unwrap();
+        for (path, _) in checked {
+            cache.valid.insert(path.to_path_buf());
+        }
+    }
+
+    let cache = cache.lock().unwrap();
+    paths
+        .iter()
+        .map(|path| cache.valid.contains(*path))
+        .collect()
+}
+
+/// The width and height of a frame, as recorded in the catalog when it was
+/// validated or else read from its header.
+pub fn frame_dimensions(cache: &Mutex<AnalysisCache>, path: &Path) -> Option<(u32, u32)> {
+    let catalog = cache.lock().unwrap().catalog.clone();
+    if let Some(catalog) = catalog {
+        match catalog.dimensions(path) {
+            Ok(Some(dimensions)) => return Some(dimensions),
+            Ok(None) => {}
+            Err(e) => eprintln!("Failed to read frame dimensions from catalog: {}", e),
+        }
+    }
+
+    image::ImageReader::open(path)
+        .and_then(|reader| reader.with_guessed_format())
+        .ok()?
+        .into_dimensions()
+        .ok()
+}
+
+/// Whether `contents` is a whole JPEG image, for frames received over HTTP.
+pub fn is_complete_jpeg(contents: &[u8]) -> bool {
+    contents.starts_with(&JPEG_START) && ends_with_marker(contents, &JPEG_END)
+}
+
+const JPEG_START: [u8; 2] = [0xFF, 0xD8];
+const JPEG_END: [u8; 2] = [0xFF, 0xD9];
+
+/// How far from the end of a file its end marker is looked for.
+const TAIL_LEN: usize = 64;
+
+/// Some cameras pad the file after the end marker, so it is looked for
+/// anywhere in the last few bytes rather than only at the very end.
+fn ends_with_marker(contents: &[u8], marker: &[u8]) -> bool {
+    contents[contents.len().saturating_sub(TAIL_LEN)..]
+        .windows(marker.len())
+        .any(|w| w == marker)
+}
+
+/// Returns the frame's width and height if it is complete.
+fn check_frame(path: &Path, min_age: Duration) -> Result<(u32, u32), String> {
+    let metadata = fs::metadata(path).map_err(|e| e.to_string())?;
+    if metadata.len() < 4 {
+        return Err("file is empty".to_string());
+    }
+
+    let modified = metadata.modified().map_err(|e| e.to_string())?;
+    let age = SystemTime::now()
+        .duration_since(modified)
+        .unwrap_or(Duration::ZERO);
+    if age < min_age {
+        return Err("file may still be being written".to_string());
+    }
+
+    let mut file = File::open(path).map_err(|e| e.to_string())?;
+    let mut header = Vec::new();
+    (&mut file)
+        .take(12)
+        .read_to_end(&mut header)
+        .map_err(|e| e.to_string())?;
+
+    let mut tail_contains = |marker: &[u8]| -> Result<bool, String> {
+        let tail_len = metadata.len().min(TAIL_LEN as u64);
+        let mut tail = vec![0u8; tail_len as usize];
+        file.seek(SeekFrom::End(-(tail_len as i64)))
+            .and_then(|_| file.read_exact(&mut tail))
+            .map_err(|e| e.to_string())?;
+        Ok(ends_with_marker(&tail, marker))
+    };
+
+    if header.starts_with(&JPEG_START) {
+        if !tail_contains(&JPEG_END)? {
+            return Err("missing JPEG end of image marker, file is truncated".to_string());
+        }
+    } else if header.starts_with(b"\x89PNG\r\n\x1a\n") {
+        if !tail_contains(b"IEND")? {
+            return Err("missing PNG end chunk, file is truncated".to_string());
+        }
+    } else if header.len() == 12 && header.starts_with(b"RIFF") && &header[8..] == b"WEBP" {
+        // WebP files start with the length of the rest of the file
+        let riff_len = u32::from_le_bytes(header[4..8].try_into().unwrap());
+        if metadata.len() < riff_len as u64 + 8 {
+            return Err("shorter than its WebP header says, file is truncated".to_string());
+        }
+    } else {
+        return Err("not a JPEG, PNG or WebP image".to_string());
+    }
+
+    image::ImageReader::open(path)
+        .and_then(|reader| reader.with_guessed_format())
+        .map_err(|e| e.to_string())?
+        .into_dimensions()
+        .map_err(|e| e.to_string())
+}
+
+/// Returns the stats of each of `paths`, in order, decoding only those that
+/// aren't cached yet. `None` means the frame could not be decoded.
+pub fn frame_stats(cache: &Mutex<AnalysisCache>, paths: &[&Path]) -> Vec<Option<FrameStats>> {
+    let (mut missing, catalog): (Vec<&Path>, _) = {
+        let cache = cache.lock().unwrap();
+        let missing = paths
+            .iter()
+            .filter(|path| !cache.stats.contains_key(**path))
+            .copied()
+            .collect();
+        (missing, cache.catalog.clone())
+    };
+
+    if let Some(catalog) = catalog.as_ref().filter(|_| !missing.is_empty()) {
+        match catalog.stats(&missing) {
+            Ok(stored) => {
+                missing.retain(|path| !stored.contains_key(*path));
+                let mut cache = cache.lock().unwrap();
+                for (path, stats) in stored {
+                    cache.stats.insert(path, Some(stats));
+                }
+            }
+            Err(e) => eprintln!("Failed to read frame stats from catalog: {}", e),
+        }
+    }
+
+    if !missing.is_empty() {
+        println!("Analyzing {} frames", missing.len());
+        let measured = in_parallel(&missing, |path| {
+            load_thumbnail(path).map(|thumbnail| FrameStats::measure(&thumbnail))
+        });
+        if let Some(catalog) = &catalog {
+            let decoded: Vec<(&Path, FrameStats)> = missing
+                .iter()
+                .zip(&measured)
+                .filter_map(|(path, stats)| Some((*path, (*stats)?)))
+                .collect();
+            if let Err(e) = catalog.put_stats(&decoded) {
+                eprintln!("Failed to write frame stats to catalog: {}", e);
+            }
+        }
+        let mut cache = cache.lock().unwrap();
+        for (path, stats) in missing.into_iter().zip(measured) {
+            cache.stats.insert(path.to_path_buf(), stats);
+        }
+    }
+
+    let cache = cache.lock().unwrap();
+    paths
+        .iter()
+        .map(|path| cache.stats.get(*path).copied().flatten())
+        .collect()
+}
+
+/// Decodes a frame into a small greyscale thumbnail.
+fn load_thumbnail(path: &Path) -> Option<GrayImage> {
+    let image = match image::open(path) {
+        Ok(image) => image,
+        Err(e) => {
+            eprintln!("Failed to decode {}: {}", path.display(), e);
+            return None;
+        }
+    };
+    Some(image.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE).to_luma8())
+}
+
+/// Maps `f` over `items` using one thread per available core.
+fn in_parallel<T, R, F>(items: &[T], f: F) -> Vec<R>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&T) -> R + Sync,
+{
+    let threads = thread::available_parallelism().map_or(1, |n| n.get());
+    let chunk_size = items.len().div_ceil(threads).max(1);
+
+    thread::scope(|scope| {
+        let handles: Vec<_> = items
+            .chunks(chunk_size)
+            .map(|chunk| scope.spawn(|| chunk.iter().map(&f).collect::<Vec<R>>()))
+            .collect();
+        handles
+            .into_iter()
+            .flat_map(|handle| handle.join().unwrap())
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_measure_blank_and_checkered_frames() {
+        let blank = GrayImage::from_pixel(8, 8, image::Luma([0]));
+        let stats = FrameStats::measure(&blank);
+        assert_eq!(stats.luminance, 0.0);
+        assert_eq!(stats.contrast, 0.0);
+
+        let checkered = GrayImage::from_fn(8, 8, |x, y| {
+            image::Luma([if (x + y) % 2 == 0 { 0 } else { 200 }])
+        });
+        let stats = FrameStats::measure(&checkered);
+        assert_eq!(stats.luminance, 100.0);
+        assert_eq!(stats.contrast, 100.0);
+    }
+
+    #[test]
+    fn test_dhash_distance() {
+        let gradient = GrayImage::from_fn(64, 48, |x, _| image::Luma([(x * 4) as u8]));
+        let brighter = GrayImage::from_fn(64, 48, |x, _| image::Luma([(x * 4 + 2) as u8]));
+        let reversed = GrayImage::from_fn(64, 48, |x, _| image::Luma([255 - (x * 4) as u8]));
+
+        let gradient = FrameStats::measure(&gradient);
+        assert_eq!(gradient.distance(&FrameStats::measure(&brighter)), 0);
+        assert_eq!(gradient.distance(&FrameStats::measure(&reversed)), 64);
+    }
+
+    #[test]
+    fn test_validate_frames_rejects_truncated_and_empty_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let good = dir.path().join("1.jpg");
+        GrayImage::from_pixel(16, 16, image::Luma([128]))
+            .save(&good)
+            .unwrap();
+        let contents = std::fs::read(&good).unwrap();
+        let truncated = dir.path().join("2.jpg");
+        std::fs::write(&truncated, &contents[..contents.len() / 2]).unwrap();
+        let empty = dir.path().join("3.jpg");
+        std::fs::write(&empty, []).unwrap();
+
+        let cache = Mutex::new(AnalysisCache::new());
+        let paths = [good.as_path(), truncated.as_path(), empty.as_path()];
+        assert_eq!(
+            validate_frames(&cache, &paths, Duration::ZERO),
+            vec![true, false, false]
+        );
+
+        // PNG and WebP frames are checked for truncation too
+        for extension in ["png", "webp"] {
+            let good = dir.path().join(format!("4.{}", extension));
+            GrayImage::from_pixel(16, 16, image::Luma([128]))
+                .save(&good)
+                .unwrap();
+            let contents = std::fs::read(&good).unwrap();
+            let truncated = dir.path().join(format!("5.{}", extension));
+            std::fs::write(&truncated, &contents[..contents.len() - 8]).unwrap();
+            let cache = Mutex::new(AnalysisCache::new());
+            assert_eq!(
+                validate_frames(
+                    &cache,
+                    &[good.as_path(), truncated.as_path()],
+                    Duration::ZERO
+                ),
+                vec![true, false]
+            );
+        }
+
+        // A frame that was only just written is skipped until it settles
+        let cache = Mutex::new(AnalysisCache::new());
+        assert_eq!(
+            validate_frames(&cache, &[good.as_path()], Duration::from_secs(60)),
+            vec![false]
+        );
+    }
+
+    #[test]
+    fn test_frame_stats_caches_results() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("1700000000.jpg");
+        GrayImage::from_pixel(16, 16, image::Luma([128]))
+            .save(&path)
+            .unwrap();
+
+        let cache = Mutex::new(AnalysisCache::new());
+        let stats = frame_stats(&cache, &[path.as_path()]);
+        assert!((stats[0].unwrap().luminance - 128.0).abs() < 1.0);
+
+        // Once cached, the frame isn't read again
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(frame_stats(&cache, &[path.as_path()]), stats);
+    }
+}
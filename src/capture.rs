@@ -0,0 +1,276 @@
+use crate::analysis::is_complete_jpeg;
+use crate::config::CameraConfig;
+use crate::index::FrameIndex;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use chrono::{DateTime, SubsecRound, Utc};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::io::Read;
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Largest snapshot accepted from a camera.
+const MAX_SNAPSHOT_BYTES: u64 = 50 * 1024 * 1024;
+
+/// Delay before retrying a failed capture, doubled after each retry.
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Captures snapshots from the configured HTTP cameras into their folders,
+/// with a thread per camera. Failed captures are retried with increasing
+/// delays for as long as they would finish before the next capture is due.
+///
+/// Cameras that can't be written to, such as ones named after an alias, are
+/// reported as failing and never captured. Captures stop once the scheduler
+/// is dropped.
+pub struct Capture {
+    status: Mutex<BTreeMap<String, CameraStatus>>,
+}
+
+/// How capturing from a camera is going.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct CameraStatus {
+    /// Whether the latest capture succeeded
+    pub healthy: bool,
+    pub frames_captured: u64,
+    pub consecutive_failures: u32,
+    pub last_capture: Option<String>,
+    pub last_error: Option<String>,
+    pub last_error_at: Option<String>,
+}
+
+impl Capture {
+    pub fn start(cameras: &HashMap<String, CameraConfig>, index: Arc<FrameIndex>) -> Arc<Self> {
+        let status = cameras
+            .keys()
+            .map(|name| (name.clone(), CameraStatus::default()))
+            .collect();
+        let capture = Arc::new(Capture {
+            status: Mutex::new(status),
+        });
+
+        for (name, camera) in cameras {
+            if let Err(e) = index.check_writable(name) {
+                eprintln!("Not capturing {}: {}", name, e);
+                let mut status = capture.status.lock().unwrap();
+                let status = status.get_mut(name).unwrap();
+                status.last_error = Some(format!("not capturing: {}", e));
+                status.last_error_at = Some(Utc::now().to_rfc3339());
+                continue;
+            }
+            println!("Capturing {} every {:?}", name, camera.interval());
+            let (name, camera) = (name.clone(), camera.clone());
+            let (index, capture) = (Arc::clone(&index), Arc::downgrade(&capture));
+            thread::spawn(move || run(&name, &camera, &index, capture));
+        }
+        capture
+    }
+
+    /// The status of every camera, by folder.
+    pub fn status(&self) -> BTreeMap<String, CameraStatus> {
+        self.status.lock().unwrap().clone()
+    }
+
+    fn record(&self, name: &str, result: Result<DateTime<Utc>, String>) {
+        let mut status = self.status.lock().unwrap();
+        let status = status.entry(name.to_string()).or_default();
+        match result {
+            Ok(taken) => {
+                status.healthy = true;
+                status.frames_captured += 1;
+                status.consecutive_failures = 0;
+                status.last_capture = Some(taken.to_rfc3339());
+            }
+            Err(e) => {
+                eprintln!("Failed to capture {}: {}", name, e);
+                status.healthy = false;
+                status.consecutive_failures += 1;
+                status.last_error = Some(e);
+                status.last_error_at = Some(Utc::now().to_rfc3339());
+            }
+        }
+    }
+}
+
+fn run(name: &str, camera: &CameraConfig, index: &FrameIndex, capture: Weak<Capture>) {
+    let agent = ureq::AgentBuilder::new().timeout(camera.timeout()).build();
+    let interval = camera.interval();
+    let mut next = Instant::now();
+    loop {
+        let Some(capture) = capture.upgrade() else {
+            return;
+        };
+        let due = next + interval;
+        capture.record(name, capture_frame(name, camera, &agent, index, due));
+        drop(capture);
+
+        // Captures that overran are skipped rather than made up for
+        next = due;
+        let now = Instant::now();
+        while next < now {
+            next += interval;
+        }
+        thread::sleep(next - now);
+    }
+}
+
+/// Captures a frame into the camera's folder, retrying until `due`. Returns
+/// the time it was taken.
+fn capture_frame(
+    name: &str,
+    camera: &CameraConfig,
+    agent: &ureq::Agent,
+    index: &FrameIndex,
+    due: Instant,
+) -> Result<DateTime<Utc>, String> {
+    let mut delay = RETRY_DELAY;
+    let mut retries = camera.retries();
+    loop {
+        let taken = Utc::now().trunc_subsecs(0);
+        let result = fetch_snapshot(camera, agent).and_then(|jpeg| {
+            index
+                .ingest(name, taken, &jpeg)
+                .map_err(|e| format!("failed to store frame: {}", e))
+        });
+        match result {
+            Ok(_) => return Ok(taken),
+            Err(e) if retries > 0 && Instant::now() + delay < due => {
+                eprintln!("Failed to capture {}, retrying in {:?}: {}", name, delay, e);
+                thread::sleep(delay);
+                delay *= 2;
+                retries -= 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn fetch_snapshot(camera: &CameraConfig, agent: &ureq::Agent) -> Result<Vec<u8>, String> {
+    let mut request = agent.get(&camera.url);
+    if let Some(username) = &camera.username {
+        let password = camera.password.as_deref().unwrap_or_default();
+        let credentials = STANDARD.encode(format!("{}:{}", username, password));
+        request = request.set("Authorization", &format!("Basic {}", credentials));
+    } else if let Some(token) = &camera.token {
+        request = request.set("Authorization", &format!("Bearer {}", token));
+    }
+
+    let mut jpeg = Vec::new();
+    request
+        .call()
+        .map_err(|e| e.to_string())?
+        .into_reader()
+        .take(MAX_SNAPSHOT_BYTES)
+        .read_to_end(&mut jpeg)
+        .map_err(|e| e.to_string())?;
+    if !is_complete_jpeg(&jpeg) {
+        return Err("camera didn't return a complete JPEG".to_string());
+    }
+    Ok(jpeg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// A stand-in camera that fails its first request, then serves `jpeg` to
+    /// requests with the right basic auth credentials. Returns its snapshot
+    /// URL and how many requests it has had.
+    fn serve_camera(jpeg: Vec<u8>) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/snapshot.jpg", listener.local_addr().unwrap());
+        let requests = Arc::new(AtomicUsize::new(0));
+        let count = Arc::clone(&requests);
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut authorized = false;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                    // "admin:secret"
+                    authorized |= line.trim() == "Authorization: Basic YWRtaW46c2VjcmV0";
+                }
+
+                let (status, body) = match count.fetch_add(1, Ordering::SeqCst) {
+                    0 => ("503 Service Unavailable", Vec::new()),
+                    _ if !authorized => ("401 Unauthorized", Vec::new()),
+                    _ => ("200 OK", jpeg.clone()),
+                };
+                let _ = write!(
+                    stream,
+                    "HTTP/1.1 {}\r\nContent-Type: image/jpeg\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    status,
+                    body.len()
+                );
+                let _ = stream.write_all(&body);
+            }
+        });
+        (url, requests)
+    }
+
+    #[test]
+    fn test_capture_retries_and_reports_status() {
+        let mut jpeg = std::io::Cursor::new(Vec::new());
+        image::RgbImage::new(8, 8)
+            .write_to(&mut jpeg, image::ImageFormat::Jpeg)
+            .unwrap();
+        let (url, requests) = serve_camera(jpeg.into_inner());
+
+        let root = tempfile::tempdir().unwrap();
+        let index = FrameIndex::new(vec![root.path().to_path_buf()], Arc::default(), None, None);
+        let camera = CameraConfig {
+            url,
+            interval_secs: Some(30),
+            timeout_secs: Some(5),
+            retries: None,
+            username: Some("admin".to_string()),
+            password: Some("secret".to_string()),
+            token: None,
+        };
+        let capture = Capture::start(
+            &HashMap::from([
+                ("driveway".to_string(), camera.clone()),
+                ("..".to_string(), camera),
+            ]),
+            Arc::clone(&index),
+        );
+        let status = &capture.status()[".."];
+        assert!(!status.healthy);
+        assert!(status
+            .last_error
+            .as_ref()
+            .unwrap()
+            .contains("not a valid folder name"));
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while capture.status()["driveway"].frames_captured == 0 {
+            assert!(Instant::now() < deadline, "no frame was captured");
+            thread::sleep(Duration::from_millis(50));
+        }
+
+        // The first attempt failed and was retried
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+        let status = &capture.status()["driveway"];
+        assert!(status.healthy);
+        assert_eq!(status.consecutive_failures, 0);
+
+        let frames = index.get_range(
+            "driveway",
+            DateTime::UNIX_EPOCH,
+            Utc::now() + Duration::from_secs(1),
+        );
+        assert_eq!(frames.frames.len(), 1);
+        let name = frames.frames[0].path.file_name().unwrap().to_str().unwrap();
+        assert_eq!(name, format!("{}.jpg", frames.frames[0].timestamp));
+    }
+}
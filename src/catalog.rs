@@ -0,0 +1,397 @@
+use crate::analysis::{FrameStats, GRID_HEIGHT, GRID_WIDTH};
+use rusqlite::{params, Connection, OptionalExtension};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// A frame file as last seen on disk.
+#[derive(Debug, Clone)]
+pub struct CatalogEntry {
+    pub path: PathBuf,
+    pub timestamp: i64,
+    pub subsec_nanos: u32,
+    pub size: u64,
+    /// Modification time in unix seconds
+    pub mtime: i64,
+}
+
+/// A persistent SQLite catalog of every frame and what has been worked out
+/// about it, so a restart doesn't have to list every folder or decode every
+/// frame again before it can answer requests.
+///
+/// Rows are reconciled against the filesystem by size and modification
+/// time. When a frame file changes, its stored analysis is thrown away.
+pub struct Catalog {
+    conn: Mutex<Connection>,
+}
+
+impl Catalog {
+    pub fn open(path: &Path) -> rusqlite::Result<Self> {
+        Self::from_connection(Connection::open(path)?)
+    }
+
+    fn from_connection(conn: Connection) -> rusqlite::Result<Self> {
+        conn.execute_batch(
+            "PRAGMA journal_mode = WAL;
+             CREATE TABLE IF NOT EXISTS frames (
+                 path TEXT PRIMARY KEY,
+                 folder TEXT NOT NULL,
+                 timestamp INTEGER NOT NULL,
+                 subsec_nanos INTEGER NOT NULL DEFAULT 0,
+                 size INTEGER NOT NULL,
+                 mtime INTEGER NOT NULL,
+                 width INTEGER,
+                 height INTEGER,
+                 luminance REAL,
+                 contrast REAL,
+                 dhash INTEGER,
+                 grid BLOB
+             );
+             CREATE INDEX IF NOT EXISTS frames_by_folder_time ON frames (folder, timestamp);",
+        )?;
+        // Catalogs created before sub-second timestamps were supported
+        if conn.prepare("SELECT subsec_nanos FROM frames").is_err() {
+            conn.execute_batch(
+                "ALTER TABLE frames ADD COLUMN subsec_nanos INTEGER NOT NULL DEFAULT 0",
+            )?;
+        }
+        Ok(Catalog {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    /// Every catalogued frame, by folder, sorted by timestamp.
+    pub fn load(&self) -> rusqlite::Result<HashMap<String, Vec<CatalogEntry>>> {
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn.prepare(
+            "SELECT folder, path, timestamp, subsec_nanos, size, mtime FROM frames
+             ORDER BY timestamp, subsec_nanos, path",
+        )?;
+        let rows = statement.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                CatalogEntry {
+                    path: PathBuf::from(row.get::<_, String>(1)?),
+                    timestamp: row.get(2)?,
+                    subsec_nanos: row.get(3)?,
+                    size: row.get(4)?,
+                    mtime: row.get(5)?,
+                },
+            ))
+        })?;
+
+        let mut folders: HashMap<String, Vec<CatalogEntry>> = HashMap::new();
+        for row in rows {
+            let (folder, entry) = row?;
+            folders.entry(folder).or_default().push(entry);
+        }
+        Ok(folders)
+    }
+
+    /// Makes the catalog's view of `folder` match `entries`, which should be
+    /// everything currently in it, or everything from `since` on if only
+    /// recent partitions were listed.
+    pub fn reconcile(
+        &self,
+        folder: &str,
+        entries: &[CatalogEntry],
+        since: Option<i64>,
+    ) -> rusqlite::Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let transaction = conn.transaction()?;
+
+        let mut existing: HashMap<String, (i64, u32, u64, i64)> = HashMap::new();
+        {
+            let mut statement = transaction.prepare(
+                "SELECT path, timestamp, subsec_nanos, size, mtime FROM frames
+                 WHERE folder = ?1 AND timestamp >= ?2",
+            )?;
+            let since = since.unwrap_or(i64::MIN);
+            let rows = statement.query_map(params![folder, since], |row| {
+                let seen = (row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?);
+                Ok((row.get::<_, String>(0)?, seen))
+            })?;
+            for row in rows {
+                let (path, seen) = row?;
+                existing.insert(path, seen);
+            }
+        }
+
+        let (mut added, mut changed) = (0, 0);
+        for entry in entries {
+            let Some(path) = entry.path.to_str() else {
+                continue;
+            };
+            match existing.remove(path) {
+                Some(seen)
+                    if seen == (entry.timestamp, entry.subsec_nanos, entry.size, entry.mtime) => {}
+                Some(_) => {
+                    upsert(&transaction, folder, entry)?;
+                    changed += 1;
+                }
+                None => {
+                    upsert(&transaction, folder, entry)?;
+                    added += 1;
+                }
+            }
+        }
+
+        for path in existing.keys() {
+            transaction.execute("DELETE FROM frames WHERE path = ?1", [path])?;
+        }
+
+        transaction.commit()?;
+        if added + changed + existing.len() > 0 {
+            println!(
+                "Catalog {}: {} added, {} changed, {} removed",
+                folder,
+                added,
+                changed,
+                existing.len()
+            );
+        }
+        Ok(())
+    }
+
+    /// Drops every folder not in `folders`.
+    pub fn retain_folders(&self, folders: &[String]) -> rusqlite::Result<()> {
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn.prepare("SELECT DISTINCT folder FROM frames")?;
+        let catalogued: Vec<String> = statement
+            .query_map([], |row| row.get(0))?
+            .collect::<Result<_, _>>()?;
+        for folder in catalogued {
+            if !folders.contains(&folder) {
+                conn.execute("DELETE FROM frames WHERE folder = ?1", [&folder])?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn insert(&self, folder: &str, entry: &CatalogEntry) -> rusqlite::Result<()> {
+        let conn = self.conn.lock().unwrap();
+        upsert(&conn, folder, entry)
+    }
+
+    pub fn remove(&self, path: &Path) -> rusqlite::Result<()> {
+        let Some(path) = path.to_str() else {
+            return Ok(());
+        };
+        let conn = self.conn.lock().unwrap();
+        conn.execute("DELETE FROM frames WHERE path = ?1", [path])?;
+        Ok(())
+    }
+
+    /// Stored stats for whichever of `paths` have them.
+    pub fn stats(&self, paths: &[&Path]) -> rusqlite::Result<HashMap<PathBuf, FrameStats>> {
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn.prepare_cached(
+            "SELECT luminance, contrast, dhash, grid FROM frames
+             WHERE path = ?1 AND luminance IS NOT NULL",
+        )?;
+
+        let mut found = HashMap::new();
+        for path in paths {
+            let Some(key) = path.to_str() else {
+                continue;
+            };
+            let row = statement
+                .query_row([key], |row| {
+                    Ok((
+                        row.get::<_, f32>(0)?,
+                        row.get::<_, f32>(1)?,
+                        row.get::<_, i64>(2)?,
+                        row.get::<_, Vec<u8>>(3)?,
+                    ))
+                })
+                .optional()?;
+            if let Some((luminance, contrast, dhash, grid)) = row {
+                let Ok(grid) = <[u8; GRID_WIDTH * GRID_HEIGHT]>::try_from(grid) else {
+                    continue;
+                };
+                found.insert(
+                    path.to_path_buf(),
+                    FrameStats {
+                        luminance,
+                        contrast,
+                        dhash: dhash as u64,
+                        grid,
+                    },
+                );
+            }
+        }
+        Ok(found)
+    }
+
+    pub fn put_stats(&self, results: &[(&Path, FrameStats)]) -> rusqlite::Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let transaction = conn.transaction()?;
+        for (path, stats) in results {
+            transaction.execute(
+                "UPDATE frames SET luminance = ?2, contrast = ?3, dhash = ?4, grid = ?5
+                 WHERE path = ?1",
+                params![
+                    path.to_str(),
+                    stats.luminance,
+                    stats.contrast,
+                    stats.dhash as i64,
+                    &stats.grid[..]
+                ],
+            )?;
+        }
+        transaction.commit()
+    }
+
+    /// Which of `paths` have already passed validation.
+    pub fn validated(&self, paths: &[&Path]) -> rusqlite::Result<HashSet<PathBuf>> {
+        let conn = self.conn.lock().unwrap();
+        let mut statement =
+            conn.prepare_cached("SELECT 1 FROM frames WHERE path = ?1 AND width IS NOT NULL")?;
+
+        let mut valid = HashSet::new();
+        for path in paths {
+            let Some(key) = path.to_str() else {
+                continue;
+            };
+            if statement.exists([key])? {
+                valid.insert(path.to_path_buf());
+            }
+        }
+        Ok(valid)
+    }
+
+    /// The dimensions recorded for a frame when it passed validation.
+    pub fn dimensions(&self, path: &Path) -> rusqlite::Result<Option<(u32, u32)>> {
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn.prepare_cached(
+            "SELECT width, height FROM frames WHERE path = ?1 AND width IS NOT NULL",
+        )?;
+        statement
+            .query_row([path.to_str()], |row| Ok((row.get(0)?, row.get(1)?)))
+            .optional()
+    }
+
+    /// Records the dimensions of frames that passed validation.
+    pub fn put_dimensions(&self, results: &[(&Path, (u32, u32))]) -> rusqlite::Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let transaction = conn.transaction()?;
+        for (path, (width, height)) in results {
+            transaction.execute(
+                "UPDATE frames SET width = ?2, height = ?3 WHERE path = ?1",
+                params![path.to_str(), width, height],
+            )?;
+        }
+        transaction.commit()
+    }
+}
+
+/// Inserts or updates a frame, forgetting any analysis if the file has
+/// changed since it was catalogued. Timestamps change without the file
+/// changing when the folder's naming is reconfigured.
+fn upsert(conn: &Connection, folder: &str, entry: &CatalogEntry) -> rusqlite::Result<()> {
+    let path = entry.path.to_str();
+    conn.execute(
+        "UPDATE frames SET
+             width = NULL, height = NULL,
+             luminance = NULL, contrast = NULL, dhash = NULL, grid = NULL
+         WHERE path = ?1 AND (size != ?2 OR mtime != ?3)",
+        params![path, entry.size, entry.mtime],
+    )?;
+    conn.execute(
+        "INSERT INTO frames (path, folder, timestamp, subsec_nanos, size, mtime)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+         ON CONFLICT (path) DO UPDATE SET
+             folder = excluded.folder,
+             timestamp = excluded.timestamp,
+             subsec_nanos = excluded.subsec_nanos,
+             size = excluded.size,
+             mtime = excluded.mtime",
+        params![
+            path,
+            folder,
+            entry.timestamp,
+            entry.subsec_nanos,
+            entry.size,
+            entry.mtime
+        ],
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(path: &str, timestamp: i64, size: u64) -> CatalogEntry {
+        CatalogEntry {
+            path: PathBuf::from(path),
+            timestamp,
+            subsec_nanos: 0,
+            size,
+            mtime: 0,
+        }
+    }
+
+    fn stats() -> FrameStats {
+        FrameStats {
+            luminance: 12.5,
+            contrast: 3.0,
+            dhash: u64::MAX,
+            grid: [7; GRID_WIDTH * GRID_HEIGHT],
+        }
+    }
+
+    #[test]
+    fn test_reconcile_adds_changes_and_removes_frames() {
+        let catalog = Catalog::from_connection(Connection::open_in_memory().unwrap()).unwrap();
+        catalog
+            .reconcile(
+                "cam1",
+                &[
+                    entry("/f/cam1/200.jpg", 200, 10),
+                    entry("/f/cam1/100.jpg", 100, 10),
+                ],
+                None,
+            )
+            .unwrap();
+
+        let folders = catalog.load().unwrap();
+        let timestamps: Vec<i64> = folders["cam1"].iter().map(|f| f.timestamp).collect();
+        assert_eq!(timestamps, vec![100, 200]);
+
+        let path = Path::new("/f/cam1/100.jpg");
+        catalog.put_stats(&[(path, stats())]).unwrap();
+        catalog.put_dimensions(&[(path, (640, 480))]).unwrap();
+        assert_eq!(catalog.stats(&[path]).unwrap()[path], stats());
+        assert_eq!(catalog.dimensions(path).unwrap(), Some((640, 480)));
+        assert!(catalog.validated(&[path]).unwrap().contains(path));
+
+        // Unchanged frames keep their analysis
+        catalog
+            .reconcile(
+                "cam1",
+                &[
+                    entry("/f/cam1/100.jpg", 100, 10),
+                    entry("/f/cam1/300.jpg", 300, 10),
+                ],
+                None,
+            )
+            .unwrap();
+        assert!(catalog.validated(&[path]).unwrap().contains(path));
+        let timestamps: Vec<i64> = catalog.load().unwrap()["cam1"]
+            .iter()
+            .map(|f| f.timestamp)
+            .collect();
+        assert_eq!(timestamps, vec![100, 300]);
+
+        // A rewritten frame loses it
+        catalog
+            .reconcile("cam1", &[entry("/f/cam1/100.jpg", 100, 20)], None)
+            .unwrap();
+        assert!(catalog.stats(&[path]).unwrap().is_empty());
+        assert!(catalog.validated(&[path]).unwrap().is_empty());
+
+        catalog.retain_folders(&[]).unwrap();
+        assert!(catalog.load().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,334 @@
+use crate::geometry::Geometry;
+use image::RgbImage;
+use serde::Deserialize;
+use std::path::Path;
+
+/// How the frames of a stack are combined into each output pixel.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StackMode {
+    /// Brightest value, which turns moving stars into trails
+    #[default]
+    Max,
+    /// Average value, which smooths out noise and passing objects
+    Mean,
+    /// Median value, which removes anything not in most frames
+    Median,
+}
+
+/// Decodes frames one at a time, skipping any that can't be decoded or
+/// don't match the size of the first usable frame.
+struct FrameReader<'a> {
+    geometry: &'a Geometry,
+    size: Option<(u32, u32)>,
+}
+
+impl<'a> FrameReader<'a> {
+    fn new(geometry: &'a Geometry) -> Self {
+        FrameReader {
+            geometry,
+            size: None,
+        }
+    }
+
+    fn read(&mut self, path: &Path) -> Option<RgbImage> {
+        let image = match image::open(path) {
+            Ok(image) => self.geometry.apply(image).to_rgb8(),
+            Err(e) => {
+                eprintln!("Failed to decode {}: {}", path.display(), e);
+                return None;
+            }
+        };
+        let size = image.dimensions();
+        if *self.size.get_or_insert(size) != size {
+            eprintln!("Skipping {}: frame size changed", path.display());
+            return None;
+        }
+        Some(image)
+    }
+}
+
+/// Combines `paths` into one image. Frames are streamed, so memory use
+/// depends on the frame size but not on how many frames there are.
+pub fn stack(paths: &[&Path], mode: StackMode, geometry: &Geometry) -> Option<RgbImage> {
+    match mode {
+        StackMode::Max => stack_max(paths, geometry),
+        StackMode::Mean => stack_mean(paths, geometry),
+        StackMode::Median => stack_median(paths, geometry),
+    }
+}
+
+fn stack_max(paths: &[&Path], geometry: &Geometry) -> Option<RgbImage> {
+    let mut reader = FrameReader::new(geometry);
+    let mut result: Option<RgbImage> = None;
+
+    for path in paths {
+        let Some(frame) = reader.read(path) else {
+            continue;
+        };
+        match &mut result {
+            None => result = Some(frame),
+            Some(result) => {
+                for (out, value) in result.iter_mut().zip(frame.iter()) {
+                    *out = (*out).max(*value);
+                }
+            }
+        }
+    }
+
+    result
+}
+
+fn stack_mean(paths: &[&Path], geometry: &Geometry) -> Option<RgbImage> {
+    let mut reader = FrameReader::new(geometry);
+    let mut sums: Vec<u32> = Vec::new();
+    let mut count = 0;
+
+    for path in paths {
+        let Some(frame) = reader.read(path) else {
+            continue;
+        };
+        if sums.is_empty() {
+            sums = vec![0; frame.len()];
+        }
+        for (sum, value) in sums.iter_mut().zip(frame.iter()) {
+            *sum += *value as u32;
+        }
+        count += 1;
+    }
+
+    let (width, height) = reader.size?;
+    let pixels = sums.into_iter().map(|sum| (sum / count) as u8).collect();
+    RgbImage::from_raw(width, height, pixels)
+}
+
+/// Finds the per-channel median without keeping every frame in memory by
+/// working out one bit of it per pass over the frames, most significant bit
+/// first (a radix select). This decodes each frame eight times.
+fn stack_median(paths: &[&Path], geometry: &Geometry) -> Option<RgbImage> {
+    let mut prefix: Vec<u8> = Vec::new();
+    let mut rank: Vec<u32> = Vec::new();
+    let mut zeros: Vec<u32> = Vec::new();
+    let mut size = None;
+
+    for bit in (0..8).rev() {
+        let mut reader = FrameReader::new(geometry);
+        let mut count = 0u32;
+        zeros.iter_mut().for_each(|zeros| *zeros = 0);
+
+        for path in paths {
+            let Some(frame) = reader.read(path) else {
+                continue;
+            };
+            if prefix.is_empty() {
+                prefix = vec![0; frame.len()];
+                zeros = vec![0; frame.len()];
+            }
+            // Count the values that match the bits decided so far and have a
+            // zero in this bit
+            for ((value, prefix), zeros) in frame.iter().zip(&prefix).zip(&mut zeros) {
+                let value = *value as u32;
+                if value >> (bit + 1) == (*prefix as u32) >> (bit + 1) && value & (1 << bit) == 0 {
+                    *zeros += 1;
+                }
+            }
+            count += 1;
+        }
+
+        if count == 0 {
+            return None;
+        }
+        if rank.is_empty() {
+            rank = vec![(count - 1) / 2; prefix.len()];
+            size = reader.size;
+        }
+
+        for ((prefix, rank), zeros) in prefix.iter_mut().zip(&mut rank).zip(&zeros) {
+            if *rank >= *zeros {
+                *prefix |= 1 << bit;
+                *rank -= *zeros;
+            }
+        }
+    }
+
+    let (width, height) = size?;
+    RgbImage::from_raw(width, height, prefix)
+}
+
+/// Which way the time slices of a timeslice image run.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Orientation {
+    /// Each column comes from a different frame, oldest on the left
+    #[default]
+    Vertical,
+    /// Each row comes from a different frame, oldest at the top
+    Horizontal,
+}
+
+/// Builds a slit-scan image where every column (or row) is taken from a
+/// different frame, in chronological order across the image. With `blend`
+/// neighbouring frames are crossfaded instead of meeting at hard edges.
+/// Only the frames that contribute a slice are decoded, at most two at a time.
+pub fn timeslice(
+    paths: &[&Path],
+    orientation: Orientation,
+    blend: bool,
+    geometry: &Geometry,
+) -> Option<RgbImage> {
+    let mut reader = FrameReader::new(geometry);
+    let first = paths.iter().find_map(|path| reader.read(path))?;
+    let (width, height) = first.dimensions();
+    let lines = match orientation {
+        Orientation::Vertical => width,
+        Orientation::Horizontal => height,
+    } as usize;
+    let frame_count = paths.len();
+
+    // For each line, the frame it starts in and how far towards the next
+    // frame it is
+    let positions: Vec<(usize, f32)> = (0..lines)
+        .map(|line| {
+            if blend {
+                let position = ((line as f32 + 0.5) * frame_count as f32 / lines as f32 - 0.5)
+                    .clamp(0.0, (frame_count - 1) as f32);
+                (position.floor() as usize, position.fract())
+            } else {
+                (line * frame_count / lines, 0.0)
+            }
+        })
+        .collect();
+
+    let mut needed = vec![false; frame_count];
+    for &(index, weight) in &positions {
+        needed[index] = true;
+        if weight > 0.0 {
+            needed[index + 1] = true;
+        }
+    }
+
+    let mut output = RgbImage::new(width, height);
+    let mut previous: Option<RgbImage> = None;
+    for (index, path) in paths.iter().enumerate() {
+        if !needed[index] {
+            continue;
+        }
+        // Stand in with the previous frame if this one can't be used
+        let Some(current) = reader.read(path).or_else(|| previous.clone()) else {
+            continue;
+        };
+
+        for (line, &(start, weight)) in positions.iter().enumerate() {
+            let line = line as u32;
+            let fade_from = if start == index && weight == 0.0 {
+                None
+            } else if start + 1 == index && weight > 0.0 {
+                match &previous {
+                    Some(previous) => Some(previous),
+                    None => continue,
+                }
+            } else {
+                continue;
+            };
+
+            let coordinates: Box<dyn Iterator<Item = (u32, u32)>> = match orientation {
+                Orientation::Vertical => Box::new((0..height).map(|y| (line, y))),
+                Orientation::Horizontal => Box::new((0..width).map(|x| (x, line))),
+            };
+            for (x, y) in coordinates {
+                let b = current.get_pixel(x, y).0;
+                let pixel = match fade_from {
+                    None => b,
+                    Some(previous) => {
+                        let a = previous.get_pixel(x, y).0;
+                        [0, 1, 2].map(|c| {
+                            (a[c] as f32 * (1.0 - weight) + b[c] as f32 * weight).round() as u8
+                        })
+                    }
+                };
+                output.put_pixel(x, y, image::Rgb(pixel));
+            }
+        }
+
+        previous = Some(current);
+    }
+
+    Some(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn write_frames(dir: &Path, levels: &[u8]) -> Vec<PathBuf> {
+        levels
+            .iter()
+            .enumerate()
+            .map(|(i, &level)| {
+                // PNG keeps the values exact, unlike JPEG
+                let path = dir.join(format!("{}.png", i));
+                RgbImage::from_pixel(4, 4, image::Rgb([level, 255 - level, 7]))
+                    .save(&path)
+                    .unwrap();
+                path
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_stack_modes() {
+        let dir = tempfile::tempdir().unwrap();
+        let paths = write_frames(dir.path(), &[10, 200, 30, 40, 250]);
+        let paths: Vec<&Path> = paths.iter().map(|p| p.as_path()).collect();
+        let geometry = Geometry::default();
+
+        let max = stack(&paths, StackMode::Max, &geometry).unwrap();
+        assert_eq!(max.get_pixel(0, 0).0, [250, 245, 7]);
+
+        let mean = stack(&paths, StackMode::Mean, &geometry).unwrap();
+        assert_eq!(mean.get_pixel(0, 0).0, [106, 149, 7]);
+
+        let median = stack(&paths, StackMode::Median, &geometry).unwrap();
+        assert_eq!(median.get_pixel(3, 3).0, [40, 215, 7]);
+    }
+
+    #[test]
+    fn test_timeslice_columns_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let paths = write_frames(dir.path(), &[0, 100, 200]);
+        let paths: Vec<&Path> = paths.iter().map(|p| p.as_path()).collect();
+        let geometry = Geometry {
+            width: Some(6),
+            ..Default::default()
+        };
+
+        let image = timeslice(&paths, Orientation::Vertical, false, &geometry).unwrap();
+        let columns: Vec<u8> = (0..6).map(|x| image.get_pixel(x, 0).0[0]).collect();
+        assert_eq!(columns, vec![0, 0, 100, 100, 200, 200]);
+
+        let image = timeslice(&paths, Orientation::Horizontal, false, &geometry).unwrap();
+        let rows: Vec<u8> = (0..6).map(|y| image.get_pixel(0, y).0[0]).collect();
+        assert_eq!(rows, vec![0, 0, 100, 100, 200, 200]);
+
+        let image = timeslice(&paths, Orientation::Vertical, true, &geometry).unwrap();
+        let columns: Vec<u8> = (0..6).map(|x| image.get_pixel(x, 0).0[0]).collect();
+        assert_eq!(columns, vec![0, 25, 75, 125, 175, 200]);
+    }
+
+    #[test]
+    fn test_stack_skips_mismatched_frames() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut paths = write_frames(dir.path(), &[10, 20]);
+        let odd = dir.path().join("odd.png");
+        RgbImage::from_pixel(8, 8, image::Rgb([255, 255, 255]))
+            .save(&odd)
+            .unwrap();
+        paths.push(odd);
+        let paths: Vec<&Path> = paths.iter().map(|p| p.as_path()).collect();
+
+        let max = stack(&paths, StackMode::Max, &Geometry::default()).unwrap();
+        assert_eq!(max.dimensions(), (4, 4));
+        assert_eq!(max.get_pixel(0, 0).0, [20, 245, 7]);
+    }
+}
@@ -0,0 +1,256 @@
+use crate::geometry::{Crop, Size};
+use crate::layout::Layout;
+use crate::naming::FrameNaming;
+use chrono_tz::Tz;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::Duration;
+use std::{env, fs};
+
+/// Optional service configuration, read from the TOML file named by the
+/// `CONFIG_FILE` env var.
+///
+/// ```toml
+/// catalog = "/var/lib/timelapse/catalog.sqlite"
+/// timezone = "America/New_York"
+///
+/// [folders.garden]
+/// latitude = 40.71
+/// longitude = -74.01
+/// crop = "0,200,1920,880"
+/// rotate = 180
+/// layout = "%Y/%m/%d"
+///
+/// [aliases]
+/// garden = ["garden-north", "garden-2023"]
+///
+/// [cameras.driveway]
+/// url = "http://192.168.1.20/snapshot.jpg"
+/// interval_secs = 30
+/// username = "admin"
+/// password = "hunter2"
+///
+/// [object_store]
+/// endpoint = "https://s3.eu-west-1.amazonaws.com"
+/// bucket = "timelapse-archive"
+/// region = "eu-west-1"
+/// cache_dir = "/var/cache/timelapse"
+/// cache_max_mb = 20480
+/// ```
+#[derive(Debug, Default, Deserialize)]
+pub struct Config {
+    /// Frames modified more recently than this are assumed to still be
+    /// uploading and are left out. Defaults to 10 seconds.
+    pub min_frame_age_secs: Option<u64>,
+    /// How often every folder is listed again to catch changes the
+    /// filesystem watcher can't see, such as writes from other NFS clients.
+    /// Defaults to 5 minutes.
+    pub index_rescan_secs: Option<u64>,
+    /// SQLite file to persist the frame index and analysis results in, so
+    /// restarts don't have to rescan and decode every frame.
+    pub catalog: Option<PathBuf>,
+    /// IANA time zone for folders that don't set their own. Defaults to the
+    /// `TZ` env var, then UTC.
+    pub timezone: Option<Tz>,
+    #[serde(default)]
+    pub folders: HashMap<String, FolderConfig>,
+    /// Names that merge several camera folders, earlier folders taking
+    /// precedence where frames have the same timestamp. Location, geometry
+    /// and other settings come from the first folder unless the alias has
+    /// its own `[folders.<alias>]` entry.
+    #[serde(default)]
+    pub aliases: HashMap<String, Vec<String>>,
+    /// Cameras to capture snapshots from, keyed by the folder frames are
+    /// written to
+    #[serde(default)]
+    pub cameras: HashMap<String, CameraConfig>,
+    /// S3-compatible bucket that older frames are archived to
+    pub object_store: Option<ObjectStoreConfig>,
+    /// Bearer token cameras push frames with. Defaults to the `INGEST_TOKEN`
+    /// env var, and uploads are refused if neither is set.
+    pub ingest_token: Option<String>,
+}
+
+/// Settings for a single camera folder.
+#[derive(Debug, Default, Clone, Deserialize)]
+pub struct FolderConfig {
+    /// Camera location, used to work out daylight hours
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    /// Default crop, rotation and scaling, overridable per request
+    pub crop: Option<Crop>,
+    pub rotate: Option<u32>,
+    pub scale: Option<Size>,
+    pub width: Option<u32>,
+    /// Date partitions frames are stored in below the folder, such as
+    /// `%Y/%m/%d`. Frames are directly inside the folder by default.
+    pub layout: Option<Layout>,
+    /// How frame file names encode their timestamps, and the folder's time
+    /// zone
+    #[serde(flatten)]
+    pub naming: FrameNaming,
+}
+
+impl FolderConfig {
+    /// The time zone of date partitions, `time_of_day`, `slow` and days, as
+    /// well as of frame times recorded without an offset.
+    pub fn timezone(&self) -> Tz {
+        self.naming.timezone()
+    }
+}
+
+/// An HTTP camera polled for JPEG snapshots.
+#[derive(Debug, Clone, Deserialize)]
+pub struct CameraConfig {
+    pub url: String,
+    /// Defaults to 60 seconds
+    pub interval_secs: Option<u64>,
+    /// Limit on each attempt, defaults to 10 seconds
+    pub timeout_secs: Option<u64>,
+    /// Further attempts after a failed capture, with increasing delays.
+    /// Defaults to 2.
+    pub retries: Option<u32>,
+    /// HTTP basic auth credentials
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Sent as a bearer token, for cameras that don't use basic auth
+    pub token: Option<String>,
+}
+
+impl CameraConfig {
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval_secs.unwrap_or(60).max(1))
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout_secs.unwrap_or(10))
+    }
+
+    pub fn retries(&self) -> u32 {
+        self.retries.unwrap_or(2)
+    }
+}
+
+/// Where archived frames are kept, as `<prefix><folder>/<file name>`. Only
+/// folders timestamped by file name can be archived.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ObjectStoreConfig {
+    /// Base URL of the S3 API. Buckets are addressed path-style, so this works
+    /// with MinIO and similar servers too.
+    pub endpoint: String,
+    pub bucket: String,
+    /// Defaults to `us-east-1`
+    pub region: Option<String>,
+    #[serde(default)]
+    pub prefix: String,
+    /// Default to the `AWS_ACCESS_KEY_ID` and `AWS_SECRET_ACCESS_KEY` env vars
+    pub access_key: Option<String>,
+    pub secret_key: Option<String>,
+    /// Local directory that downloaded frames are kept in
+    pub cache_dir: PathBuf,
+    /// Size the cache is trimmed to, least recently used frames first.
+    /// Defaults to 10 GiB.
+    pub cache_max_mb: Option<u64>,
+}
+
+impl Config {
+    pub fn load() -> Self {
+        match env::var("CONFIG_FILE") {
+            Ok(path) => {
+                let contents = fs::read_to_string(&path)
+                    .unwrap_or_else(|e| panic!("Failed to read config file {}: {}", path, e));
+                toml::from_str(&contents)
+                    .unwrap_or_else(|e| panic!("Failed to parse config file {}: {}", path, e))
+            }
+            Err(_) => Config::default(),
+        }
+    }
+
+    pub fn min_frame_age(&self) -> Duration {
+        Duration::from_secs(self.min_frame_age_secs.unwrap_or(10))
+    }
+
+    pub fn index_rescan_interval(&self) -> Duration {
+        Duration::from_secs(self.index_rescan_secs.unwrap_or(300))
+    }
+
+    pub fn ingest_token(&self) -> Option<String> {
+        self.ingest_token
+            .clone()
+            .or_else(|| env::var("INGEST_TOKEN").ok())
+            .filter(|token| !token.is_empty())
+    }
+
+    /// Settings of a camera folder. Aliases without their own settings use
+    /// those of their first folder.
+    pub fn folder(&self, name: &str) -> FolderConfig {
+        let name = match self.aliases.get(name) {
+            Some(names) if !self.folders.contains_key(name) => {
+                names.first().map_or(name, String::as_str)
+            }
+            _ => name,
+        };
+        let mut folder = self.folders.get(name).cloned().unwrap_or_default();
+        folder.naming.timezone = folder.naming.timezone.or_else(|| self.timezone());
+        folder
+    }
+
+    fn timezone(&self) -> Option<Tz> {
+        self.timezone.or_else(|| env::var("TZ").ok()?.parse().ok())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_folder_config() {
+        let config: Config = toml::from_str(
+            r#"
+            [folders.garden]
+            latitude = 40.71
+            longitude = -74.01
+
+            [folders.archive]
+            filename_prefix = "cam1_"
+            timestamp_unit = "ms"
+
+            [aliases]
+            yard = ["garden", "archive"]
+            "#,
+        )
+        .unwrap();
+
+        let garden = config.folder("garden");
+        assert_eq!(garden.latitude, Some(40.71));
+        assert_eq!(garden.longitude, Some(-74.01));
+        assert!(config.folder("driveway").latitude.is_none());
+        assert_eq!(config.folder("yard").latitude, Some(40.71));
+
+        let archive = config.folder("archive").naming;
+        assert_eq!(
+            archive.timestamp("cam1_1700000000500.jpg"),
+            Some((1700000000, 500_000_000))
+        );
+    }
+
+    #[test]
+    fn test_folder_timezones() {
+        let config: Config = toml::from_str(
+            r#"
+            timezone = "America/New_York"
+
+            [folders.cabin]
+            timezone = "Europe/Oslo"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.folder("cabin").timezone(), Tz::Europe__Oslo);
+        assert_eq!(config.folder("garden").timezone(), Tz::America__New_York);
+        assert_eq!(FolderConfig::default().timezone(), Tz::UTC);
+        assert!(toml::from_str::<Config>("timezone = \"Mars/Olympus\"").is_err());
+    }
+}
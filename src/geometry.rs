@@ -0,0 +1,257 @@
+use crate::config::FolderConfig;
+use image::imageops::FilterType;
+use image::DynamicImage;
+use serde::Deserialize;
+use std::str::FromStr;
+
+/// A region of the frame written as `x,y,w,h` in pixels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Crop {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl FromStr for Crop {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let values: Vec<u32> = s
+            .split(',')
+            .map(|v| v.trim().parse())
+            .collect::<Result<_, _>>()
+            .map_err(|_| format!("invalid crop: {}", s))?;
+        match values[..] {
+            [x, y, width, height] if width > 0 && height > 0 => Ok(Crop {
+                x,
+                y,
+                width,
+                height,
+            }),
+            _ => Err(format!("crop must be x,y,w,h: {}", s)),
+        }
+    }
+}
+
+/// An output size written as `WxH`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Size {
+    pub width: u32,
+    pub height: u32,
+}
+
+impl FromStr for Size {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (width, height) = s
+            .split_once('x')
+            .ok_or_else(|| format!("scale must be WxH: {}", s))?;
+        match (width.trim().parse(), height.trim().parse()) {
+            (Ok(width), Ok(height)) if width > 0 && height > 0 => Ok(Size { width, height }),
+            _ => Err(format!("invalid scale: {}", s)),
+        }
+    }
+}
+
+macro_rules! deserialize_from_str {
+    ($type:ty) => {
+        impl<'de> Deserialize<'de> for $type {
+            fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+            where
+                D: serde::Deserializer<'de>,
+            {
+                let s: String = Deserialize::deserialize(deserializer)?;
+                s.parse().map_err(serde::de::Error::custom)
+            }
+        }
+    };
+}
+
+deserialize_from_str!(Crop);
+deserialize_from_str!(Size);
+
+/// Crop, rotation and scaling applied to every frame, in that order. Crop
+/// coordinates are relative to the original, unrotated frame.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Geometry {
+    pub crop: Option<Crop>,
+    /// Clockwise rotation in degrees: 90, 180 or 270
+    pub rotate: Option<u32>,
+    pub scale: Option<Size>,
+    /// Output width, keeping the aspect ratio. Ignored when `scale` is set.
+    pub width: Option<u32>,
+}
+
+impl Geometry {
+    /// Fills in anything not set on this request from the folder's defaults.
+    pub fn or_folder_defaults(self, folder_config: &FolderConfig) -> Self {
+        Geometry {
+            crop: self.crop.or(folder_config.crop),
+            rotate: self.rotate.or(folder_config.rotate),
+            scale: self.scale.or(folder_config.scale),
+            width: self.width.or(folder_config.width),
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        match self.rotate {
+            None | Some(0 | 90 | 180 | 270) => Ok(()),
+            Some(degrees) => Err(format!("rotate must be 90, 180 or 270, not {}", degrees)),
+        }
+    }
+
+    /// Checks that the crop lies inside a `width` x `height` frame. ffmpeg
+    /// refuses crops that don't, so they are rejected for every output.
+    pub fn check_fits(&self, width: u32, height: u32) -> Result<(), String> {
+        match self.crop {
+            Some(crop)
+                if crop.x as u64 + crop.width as u64 > width as u64
+                    || crop.y as u64 + crop.height as u64 > height as u64 =>
+            {
+                Err(format!(
+                    "crop {},{},{},{} doesn't fit inside the {}x{} frames",
+                    crop.x, crop.y, crop.width, crop.height, width, height
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// The equivalent ffmpeg video filters.
+    pub fn ffmpeg_filters(&self) -> Vec<String> {
+        let mut filters = Vec::new();
+
+        if let Some(crop) = self.crop {
+            filters.push(format!(
+                "crop={}:{}:{}:{}",
+                crop.width, crop.height, crop.x, crop.y
+            ));
+        }
+
+        match self.rotate {
+            Some(90) => filters.push("transpose=clock".to_string()),
+            Some(180) => filters.push("hflip,vflip".to_string()),
+            Some(270) => filters.push("transpose=cclock".to_string()),
+            _ => {}
+        }
+
+        if let Some(size) = self.scale {
+            filters.push(format!("scale={}:{}", size.width, size.height));
+        } else if let Some(width) = self.width {
+            // -2 keeps the aspect ratio while rounding to the even height x264 needs
+            filters.push(format!("scale={}:-2", width));
+        }
+
+        filters
+    }
+
+    /// Applies the same crop, rotation and scaling as [`Self::ffmpeg_filters`]
+    /// to a decoded frame, for outputs that don't go through ffmpeg.
+    pub fn apply(&self, mut image: DynamicImage) -> DynamicImage {
+        if let Some(crop) = self.crop {
+            image = image.crop_imm(crop.x, crop.y, crop.width, crop.height);
+        }
+
+        image = match self.rotate {
+            Some(90) => image.rotate90(),
+            Some(180) => image.rotate180(),
+            Some(270) => image.rotate270(),
+            _ => image,
+        };
+
+        if let Some(size) = self.scale {
+            image = image.resize_exact(size.width, size.height, FilterType::Triangle);
+        } else if let Some(width) = self.width {
+            let height =
+                (image.height() as u64 * width as u64 / image.width().max(1) as u64).max(1) as u32;
+            image = image.resize_exact(width, height, FilterType::Triangle);
+        }
+
+        image
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_crop_and_size() {
+        assert_eq!(
+            "10,20,640,360".parse::<Crop>().unwrap(),
+            Crop {
+                x: 10,
+                y: 20,
+                width: 640,
+                height: 360
+            }
+        );
+        assert!("10,20,640".parse::<Crop>().is_err());
+        assert_eq!(
+            "1280x720".parse::<Size>().unwrap(),
+            Size {
+                width: 1280,
+                height: 720
+            }
+        );
+        assert!("1280".parse::<Size>().is_err());
+    }
+
+    #[test]
+    fn test_ffmpeg_filters_in_order() {
+        let geometry = Geometry {
+            crop: "0,100,1920,900".parse().ok(),
+            rotate: Some(180),
+            scale: None,
+            width: Some(1280),
+        };
+        assert_eq!(
+            geometry.ffmpeg_filters(),
+            vec!["crop=1920:900:0:100", "hflip,vflip", "scale=1280:-2"]
+        );
+    }
+
+    #[test]
+    fn test_apply_to_image() {
+        let image = DynamicImage::new_rgb8(400, 300);
+        let geometry = Geometry {
+            crop: "0,0,200,100".parse().ok(),
+            rotate: Some(90),
+            scale: None,
+            width: Some(50),
+        };
+        let image = geometry.apply(image);
+        assert_eq!((image.width(), image.height()), (50, 100));
+    }
+
+    #[test]
+    fn test_crop_must_fit_frame() {
+        let geometry = Geometry {
+            crop: "100,50,300,250".parse().ok(),
+            ..Default::default()
+        };
+        assert!(geometry.check_fits(400, 300).is_ok());
+        assert!(geometry.check_fits(399, 300).is_err());
+        assert!(geometry.check_fits(400, 299).is_err());
+        assert!(Geometry::default().check_fits(1, 1).is_ok());
+    }
+
+    #[test]
+    fn test_request_overrides_folder_defaults() {
+        let folder_config = FolderConfig {
+            rotate: Some(180),
+            width: Some(640),
+            ..Default::default()
+        };
+        let geometry = Geometry {
+            width: Some(1280),
+            ..Default::default()
+        }
+        .or_folder_defaults(&folder_config);
+
+        assert_eq!(geometry.rotate, Some(180));
+        assert_eq!(geometry.width, Some(1280));
+    }
+}
@@ -0,0 +1,859 @@
+use crate::catalog::{Catalog, CatalogEntry};
+use crate::config::Config;
+use crate::naming::{TimestampCache, TimestampSource};
+use crate::storage::ObjectStore;
+use crate::{Frame, FrameCollection};
+use chrono::{DateTime, TimeDelta, Utc};
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+use tempfile::NamedTempFile;
+
+/// An in-memory, sorted index of the frames in every camera folder under the
+/// frame roots, so requests don't have to list the folder each time.
+///
+/// A camera's frames can be split across several roots, such as a fast local
+/// disk for recent frames and an NFS archive for older ones. They are merged
+/// per request, and where more than one root has a frame with the same
+/// timestamp, the root listed first wins. Configured aliases merge several
+/// camera folders under one name the same way.
+///
+/// The index is built at startup and kept current by filesystem events.
+/// Events aren't delivered for changes made by other machines on network
+/// filesystems such as NFS, so folders are also rescanned periodically. In
+/// date-partitioned folders, periodic rescans only list recent partitions.
+///
+/// With a catalog, the index starts from what the catalog held at shutdown
+/// and reconciles it with the filesystem in the background.
+///
+/// Frames archived to object storage aren't indexed. They are listed per
+/// request and take the lowest precedence. Their paths are in the store's
+/// cache, and they are only downloaded once selected for a response.
+pub struct FrameIndex {
+    roots: Vec<PathBuf>,
+    /// Frames of each camera folder, one collection per root
+    folders: RwLock<HashMap<String, Vec<FrameCollection>>>,
+    watcher: Mutex<Option<RecommendedWatcher>>,
+    config: Arc<Config>,
+    catalog: Option<Arc<Catalog>>,
+    store: Option<ObjectStore>,
+    /// Timestamps of folders timestamped by EXIF or modification time
+    timestamps: TimestampCache,
+}
+
+impl FrameIndex {
+    /// Indexes every folder under `roots`, in order of precedence.
+    pub fn new(
+        roots: Vec<PathBuf>,
+        config: Arc<Config>,
+        catalog: Option<Arc<Catalog>>,
+        store: Option<ObjectStore>,
+    ) -> Arc<Self> {
+        let index = Arc::new(FrameIndex {
+            roots,
+            folders: RwLock::new(HashMap::new()),
+            watcher: Mutex::new(None),
+            config,
+            catalog,
+            store,
+            timestamps: TimestampCache::default(),
+        });
+
+        let catalogued = match &index.catalog {
+            Some(catalog) => catalog.load().unwrap_or_else(|e| {
+                eprintln!("Failed to load frame catalog: {}", e);
+                HashMap::new()
+            }),
+            None => HashMap::new(),
+        };
+        let mut folders: HashMap<String, Vec<FrameCollection>> = HashMap::new();
+        let mut frame_count = 0;
+        for (folder, entries) in catalogued {
+            // Folders under roots that are no longer configured
+            let Some((root, name)) = index.locate(Path::new(&folder)) else {
+                continue;
+            };
+            let from_contents =
+                index.config.folder(&name).naming.timestamp_source != TimestampSource::Filename;
+            let frames: Vec<Frame> = entries
+                .into_iter()
+                .map(|entry| {
+                    let timestamp = (entry.timestamp, entry.subsec_nanos);
+                    if from_contents {
+                        index.timestamps.insert(
+                            entry.path.clone(),
+                            entry.size,
+                            entry.mtime,
+                            timestamp,
+                        );
+                    }
+                    Frame {
+                        path: entry.path,
+                        timestamp: entry.timestamp,
+                        subsec_nanos: entry.subsec_nanos,
+                        hold: 1.0,
+                    }
+                })
+                .collect();
+            frame_count += frames.len();
+            index.root_collections(&mut folders, &name)[root] = FrameCollection { frames };
+        }
+
+        if folders.is_empty() {
+            index.rescan();
+        } else {
+            println!(
+                "Loaded {} frames in {} folders from the catalog",
+                frame_count,
+                folders.len()
+            );
+            *index.folders.write().unwrap() = folders;
+            let index = Arc::clone(&index);
+            thread::spawn(move || index.rescan());
+        }
+        index
+    }
+
+    /// Names of the indexed folders and configured aliases, sorted.
+    pub fn folders(&self) -> Vec<String> {
+        let mut folders: Vec<String> = self.folders.read().unwrap().keys().cloned().collect();
+        folders.extend(self.config.aliases.keys().cloned());
+        folders.sort();
+        folders.dedup();
+        folders
+    }
+
+    pub fn get_range(
+        &self,
+        folder: &str,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> FrameCollection {
+        let mut frames = self.with_folder(folder, start, end);
+        if let Some(store) = &self.store {
+            let mut collections = vec![frames];
+            for name in self.names(folder) {
+                let naming = &self.config.folder(&name).naming;
+                match store.list_range(&name, naming, start, end) {
+                    Ok(frames) => collections.push(FrameCollection { frames }),
+                    Err(e) => eprintln!("Failed to list {} in object storage: {}", name, e),
+                }
+            }
+            frames = FrameCollection::merge(collections);
+        }
+        println!(
+            "Found {} frames between {} and {}",
+            frames.frames.len(),
+            start.format("%Y-%m-%d %H:%M:%S UTC"),
+            end.format("%Y-%m-%d %H:%M:%S UTC")
+        );
+        frames
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Where frames missing from the roots are archived, if anywhere.
+    pub fn store(&self) -> Option<&ObjectStore> {
+        self.store.as_ref()
+    }
+
+    pub fn get_past_days(&self, folder: &str, days: i64) -> FrameCollection {
+        let now = Utc::now();
+        self.get_range(folder, now - TimeDelta::days(days), now)
+    }
+
+    /// The frames from `start` to `end` of every collection behind `folder`,
+    /// which may be an alias, merged in order of precedence.
+    fn with_folder(
+        &self,
+        folder: &str,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> FrameCollection {
+        let mut results = Vec::new();
+        for name in &self.names(folder) {
+            if let Some(collections) = self.folders.read().unwrap().get(name) {
+                results.extend(
+                    collections
+                        .iter()
+                        .map(|frames| frames.get_range(start, end)),
+                );
+                continue;
+            }
+
+            // A folder created since the last scan, which the watcher missed.
+            // Date-partitioned folders are only listed for the range asked
+            // for, and left for the next rescan to index.
+            if !self.is_folder(name) {
+                continue;
+            }
+            let folder_config = self.config.folder(name);
+            let range = folder_config.layout.is_some().then_some((start, end));
+            let collections: Vec<FrameCollection> = self
+                .roots
+                .iter()
+                .map(|root| {
+                    let path = root.join(name);
+                    if !path.is_dir() {
+                        return FrameCollection::default();
+                    }
+                    let (since, until) = range.unzip();
+                    FrameCollection::scan(
+                        path.clone(),
+                        &folder_config,
+                        since,
+                        until,
+                        &self.timestamps,
+                    )
+                    .unwrap_or_else(|e| {
+                        eprintln!("Failed to list {}: {}", path.display(), e);
+                        FrameCollection::default()
+                    })
+                })
+                .collect();
+            results.extend(
+                collections
+                    .iter()
+                    .map(|frames| frames.get_range(start, end)),
+            );
+            if range.is_none() {
+                self.folders
+                    .write()
+                    .unwrap()
+                    .insert(name.to_string(), collections);
+            }
+        }
+        FrameCollection::merge(results)
+    }
+
+    /// The camera folders behind `folder`, which may be an alias.
+    fn names(&self, folder: &str) -> Vec<String> {
+        match self.config.aliases.get(folder) {
+            Some(names) => names.clone(),
+            None => vec![folder.to_string()],
+        }
+    }
+
+    fn is_folder(&self, folder: &str) -> bool {
+        is_folder_name(folder) && self.roots.iter().any(|root| root.join(folder).is_dir())
+    }
+
+    /// Whether frames can be written to `folder`, which must be a camera
+    /// folder rather than an alias.
+    pub fn check_writable(&self, folder: &str) -> Result<(), String> {
+        if !is_folder_name(folder) {
+            return Err(format!("{:?} is not a valid folder name", folder));
+        }
+        if self.config.aliases.contains_key(folder) {
+            return Err(format!("{} is an alias, not a camera folder", folder));
+        }
+        Ok(())
+    }
+
+    /// Writes a frame taken at `taken` into `folder` on the first root, named
+    /// and partitioned like the folder's other frames, and indexes it. The
+    /// folder is created if needed.
+    pub fn ingest(
+        &self,
+        folder: &str,
+        taken: DateTime<Utc>,
+        contents: &[u8],
+    ) -> io::Result<PathBuf> {
+        let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidInput, message);
+        self.check_writable(folder).map_err(|e| invalid(&e))?;
+        let root = self.roots.first().ok_or_else(|| invalid("no frame root"))?;
+
+        let folder_config = self.config.folder(folder);
+        let naming = &folder_config.naming;
+        let file_name = naming
+            .file_name(taken.timestamp(), taken.timestamp_subsec_nanos(), "jpg")
+            .ok_or_else(|| invalid("timestamp out of range"))?;
+        let mut dir = root.join(folder);
+        if let Some(layout) = &folder_config.layout {
+            dir.push(layout.partition(taken, folder_config.timezone()));
+        }
+        fs::create_dir_all(&dir)?;
+
+        // Renamed into place once complete, so scans never see a partial frame
+        let path = dir.join(file_name);
+        let mut temp = NamedTempFile::new_in(&dir)?;
+        temp.write_all(contents)?;
+        let file = temp.persist_noclobber(&path).map_err(|e| e.error)?;
+        // Folders timestamped from the file itself fall back to its
+        // modification time
+        if naming.timestamp_source != TimestampSource::Filename {
+            let since_epoch = (taken - DateTime::UNIX_EPOCH).to_std().unwrap_or_default();
+            file.set_modified(UNIX_EPOCH + since_epoch)?;
+        }
+
+        self.refresh_path(&path);
+        Ok(path)
+    }
+
+    /// The root and camera folder name of a camera folder path.
+    fn locate(&self, folder: &Path) -> Option<(usize, String)> {
+        let root = self
+            .roots
+            .iter()
+            .position(|root| folder.parent() == Some(root.as_path()))?;
+        let name = folder.file_name()?.to_str()?.to_string();
+        Some((root, name))
+    }
+
+    /// The per-root collections of a camera folder, created empty if needed.
+    fn root_collections<'a>(
+        &self,
+        folders: &'a mut HashMap<String, Vec<FrameCollection>>,
+        name: &str,
+    ) -> &'a mut Vec<FrameCollection> {
+        folders.entry(name.to_string()).or_insert_with(|| {
+            self.roots
+                .iter()
+                .map(|_| FrameCollection::default())
+                .collect()
+        })
+    }
+
+    /// Lists every folder again and replaces what the index holds.
+    pub fn rescan(&self) {
+        self.scan(None);
+    }
+
+    /// Lists every folder again. Only the partitions of date-partitioned
+    /// folders that may hold frames from `since` on are listed, and older
+    /// frames are kept as they were.
+    fn scan(&self, since: Option<DateTime<Utc>>) {
+        let started = Instant::now();
+        let mut scanned: HashMap<String, Vec<FrameCollection>> = HashMap::new();
+        let mut partial = HashSet::new();
+        let mut failed = HashSet::new();
+        let mut failed_roots = Vec::new();
+        let mut catalogued = Vec::new();
+        for (root, root_path) in self.roots.iter().enumerate() {
+            let names: Vec<String> = match fs::read_dir(root_path) {
+                Ok(read_dir) => read_dir
+                    .filter_map(|entry| entry.ok())
+                    .filter(|entry| entry.file_type().is_ok_and(|t| t.is_dir()))
+                    .filter_map(|entry| entry.file_name().into_string().ok())
+                    .collect(),
+                Err(e) => {
+                    // Keep what is already indexed rather than dropping a
+                    // root that is only temporarily unavailable
+                    eprintln!("Failed to list {}: {}", root_path.display(), e);
+                    failed_roots.push(root);
+                    continue;
+                }
+            };
+
+            for name in names {
+                let folder_config = self.config.folder(&name);
+                // Folders that aren't indexed yet are listed in full, as there
+                // are no older frames to keep
+                let since = since.filter(|_| {
+                    folder_config.layout.is_some()
+                        && self.folders.read().unwrap().contains_key(&name)
+                });
+                let folder = root_path.join(&name);
+                let frames = match FrameCollection::scan(
+                    folder.clone(),
+                    &folder_config,
+                    since,
+                    None,
+                    &self.timestamps,
+                ) {
+                    Ok(frames) => frames,
+                    Err(e) => {
+                        // Keep the frames indexed before, as for roots
+                        eprintln!("Failed to list {}: {}", folder.display(), e);
+                        failed.insert((root, name.clone()));
+                        self.root_collections(&mut scanned, &name);
+                        catalogued.push(folder.to_string_lossy().into_owned());
+                        continue;
+                    }
+                };
+                if since.is_some() {
+                    partial.insert((root, name.clone()));
+                }
+                if let Some(catalog) = &self.catalog {
+                    let folder = folder.to_string_lossy().into_owned();
+                    let entries: Vec<CatalogEntry> =
+                        frames.frames.iter().filter_map(catalog_entry).collect();
+                    let since = since.map(|since| since.timestamp());
+                    if let Err(e) = catalog.reconcile(&folder, &entries, since) {
+                        eprintln!("Failed to update frame catalog for {}: {}", folder, e);
+                    }
+                    catalogued.push(folder);
+                }
+                self.root_collections(&mut scanned, &name)[root] = frames;
+            }
+        }
+        for &root in &failed_roots {
+            for name in self.folders.read().unwrap().keys() {
+                failed.insert((root, name.clone()));
+                self.root_collections(&mut scanned, name);
+                catalogued.push(self.roots[root].join(name).to_string_lossy().into_owned());
+            }
+        }
+        if let Some(catalog) = &self.catalog {
+            if let Err(e) = catalog.retain_folders(&catalogued) {
+                eprintln!("Failed to update frame catalog: {}", e);
+            }
+        }
+
+        let mut folders = self.folders.write().unwrap();
+        for (root, name) in &failed {
+            if let Some(old) = folders.get_mut(name) {
+                scanned.get_mut(name).unwrap()[*root] = std::mem::take(&mut old[*root]);
+            }
+        }
+        if let Some(since) = since {
+            for (root, name) in &partial {
+                let Some(old) = folders.get_mut(name) else {
+                    continue;
+                };
+                let old = std::mem::take(&mut old[*root]);
+                let frames = &mut scanned.get_mut(name).unwrap()[*root];
+                let older = old
+                    .frames
+                    .into_iter()
+                    .take_while(|frame| frame.timestamp < since.timestamp());
+                frames.frames.splice(0..0, older);
+            }
+        }
+        let frame_count: usize = scanned
+            .values()
+            .flatten()
+            .map(|frames| frames.frames.len())
+            .sum();
+        println!(
+            "Indexed {} frames in {} folders in {:.1}s",
+            frame_count,
+            scanned.len(),
+            started.elapsed().as_secs_f64()
+        );
+        *folders = scanned;
+    }
+
+    /// Brings the index up to date with a path that was created, changed or
+    /// removed.
+    pub fn refresh_path(&self, path: &Path) {
+        let Some((root, relative)) = self
+            .roots
+            .iter()
+            .enumerate()
+            .find_map(|(i, root)| Some((i, path.strip_prefix(root).ok()?)))
+        else {
+            return;
+        };
+        let mut components = relative.components();
+        let Some(folder) = components.next().and_then(|c| c.as_os_str().to_str()) else {
+            return;
+        };
+        // Only files directly inside a camera folder, or inside its date
+        // partitions, are frames
+        let folder_config = self.config.folder(folder);
+        let depth = folder_config.layout.as_ref().map_or(0, |l| l.depth());
+        if components.count() != depth + 1 {
+            return;
+        }
+
+        // Folders that aren't indexed yet are listed by the next lazy load or
+        // rescan
+        let mut folders = self.folders.write().unwrap();
+        let Some(collections) = folders.get_mut(folder) else {
+            return;
+        };
+        let frames = &mut collections[root];
+        if path.is_file() {
+            if let Some(frame) =
+                Frame::from_path(path.to_path_buf(), &folder_config.naming, &self.timestamps)
+            {
+                if let Some(catalog) = &self.catalog {
+                    let catalog_folder = self.roots[root].join(folder);
+                    let result = catalog_entry(&frame).map_or(Ok(()), |entry| {
+                        catalog.insert(&catalog_folder.to_string_lossy(), &entry)
+                    });
+                    if let Err(e) = result {
+                        eprintln!("Failed to catalog {}: {}", path.display(), e);
+                    }
+                }
+                frames.insert(frame);
+            }
+        } else {
+            frames.remove(path);
+            if let Some(catalog) = &self.catalog {
+                if let Err(e) = catalog.remove(path) {
+                    eprintln!("Failed to uncatalog {}: {}", path.display(), e);
+                }
+            }
+        }
+    }
+
+    /// Starts watching the frame roots for changes.
+    pub fn watch(self: &Arc<Self>) -> notify::Result<()> {
+        let index = Arc::downgrade(self);
+        let mut watcher =
+            notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+                let Some(index) = index.upgrade() else {
+                    return;
+                };
+                match event {
+                    // Reading frames to render them shows up as access events
+                    Ok(event) if event.kind.is_access() => {}
+                    Ok(event) => {
+                        for path in &event.paths {
+                            index.refresh_path(path);
+                        }
+                    }
+                    Err(e) => eprintln!("Frame watcher error: {}", e),
+                }
+            })?;
+        for root in &self.roots {
+            watcher.watch(root, RecursiveMode::Recursive)?;
+        }
+        *self.watcher.lock().unwrap() = Some(watcher);
+        Ok(())
+    }
+
+    /// Rescans every folder on a fixed interval, for changes the watcher
+    /// can't see. Date partitions older than a day are assumed not to change.
+    pub fn spawn_rescan(self: &Arc<Self>, interval: Duration) {
+        let index = Arc::downgrade(self);
+        let window = TimeDelta::from_std(interval).unwrap_or(TimeDelta::MAX) + TimeDelta::days(1);
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            match index.upgrade() {
+                Some(index) => index.scan(Some(Utc::now() - window)),
+                None => return,
+            }
+        });
+    }
+}
+
+fn is_folder_name(folder: &str) -> bool {
+    !folder.is_empty() && folder != "." && folder != ".." && !folder.contains(['/', '\\'])
+}
+
+fn catalog_entry(frame: &Frame) -> Option<CatalogEntry> {
+    let metadata = fs::metadata(&frame.path).ok()?;
+    let mtime = metadata
+        .modified()
+        .ok()?
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64);
+    Some(CatalogEntry {
+        path: frame.path.clone(),
+        timestamp: frame.timestamp,
+        subsec_nanos: frame.subsec_nanos,
+        size: metadata.len(),
+        mtime,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::FolderConfig;
+    use chrono::TimeZone;
+
+    fn at(timestamp: i64) -> DateTime<Utc> {
+        DateTime::from_timestamp(timestamp, 0).unwrap()
+    }
+
+    fn timestamps(frames: &FrameCollection) -> Vec<i64> {
+        frames.frames.iter().map(|f| f.timestamp).collect()
+    }
+
+    #[test]
+    fn test_range_queries_and_refresh() {
+        let root = tempfile::tempdir().unwrap();
+        let folder = root.path().join("cam1");
+        fs::create_dir(&folder).unwrap();
+        for timestamp in [300, 100, 200, 400] {
+            fs::write(folder.join(format!("{}.jpg", timestamp)), b"").unwrap();
+        }
+        fs::write(folder.join("notes.txt"), b"").unwrap();
+
+        let index = FrameIndex::new(vec![root.path().to_path_buf()], Arc::default(), None, None);
+        assert_eq!(index.folders(), vec!["cam1"]);
+        assert_eq!(
+            timestamps(&index.get_range("cam1", at(100), at(400))),
+            vec![200, 300]
+        );
+
+        let added = folder.join("250.jpg");
+        fs::write(&added, b"").unwrap();
+        index.refresh_path(&added);
+        fs::remove_file(folder.join("300.jpg")).unwrap();
+        index.refresh_path(&folder.join("300.jpg"));
+        assert_eq!(
+            timestamps(&index.get_range("cam1", at(0), at(1000))),
+            vec![100, 200, 250, 400]
+        );
+
+        assert!(index
+            .get_range("../cam1", at(0), at(1000))
+            .frames
+            .is_empty());
+        assert!(index
+            .get_range("missing", at(0), at(1000))
+            .frames
+            .is_empty());
+    }
+
+    #[test]
+    fn test_new_folders_are_found_without_rescan() {
+        let root = tempfile::tempdir().unwrap();
+        let index = FrameIndex::new(vec![root.path().to_path_buf()], Arc::default(), None, None);
+
+        let folder = root.path().join("cam2");
+        fs::create_dir(&folder).unwrap();
+        fs::write(folder.join("100.jpg"), b"").unwrap();
+        assert_eq!(
+            timestamps(&index.get_range("cam2", at(0), at(1000))),
+            vec![100]
+        );
+    }
+
+    #[test]
+    fn test_roots_and_aliases_are_merged() {
+        let (ssd, archive) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        for (root, folder, timestamps) in [
+            (&ssd, "north", &[300, 400][..]),
+            (&archive, "north", &[100, 200, 300][..]),
+            (&archive, "south", &[150, 400][..]),
+        ] {
+            fs::create_dir_all(root.path().join(folder)).unwrap();
+            for timestamp in timestamps {
+                let path = root.path().join(folder).join(format!("{}.jpg", timestamp));
+                fs::write(path, b"").unwrap();
+            }
+        }
+
+        let mut config = Config::default();
+        config.aliases.insert(
+            "garden".to_string(),
+            vec!["north".to_string(), "south".to_string()],
+        );
+        let roots = vec![ssd.path().to_path_buf(), archive.path().to_path_buf()];
+        let index = FrameIndex::new(roots, Arc::new(config), None, None);
+        assert_eq!(index.folders(), vec!["garden", "north", "south"]);
+
+        // Duplicates come from the root listed first
+        let north = index.get_range("north", at(0), at(1000));
+        assert_eq!(timestamps(&north), vec![100, 200, 300, 400]);
+        assert!(north.frames[2].path.starts_with(ssd.path()));
+
+        let garden = index.get_range("garden", at(0), at(1000));
+        assert_eq!(timestamps(&garden), vec![100, 150, 200, 300, 400]);
+        assert!(garden.frames[4].path.starts_with(ssd.path()));
+
+        let added = archive.path().join("north/500.jpg");
+        fs::write(&added, b"").unwrap();
+        index.refresh_path(&added);
+        assert_eq!(
+            timestamps(&index.get_range("garden", at(450), at(1000))),
+            vec![500]
+        );
+
+        // A root that can't be listed keeps what it had, and the others are
+        // still rescanned
+        let unavailable = archive.path().with_extension("unmounted");
+        fs::rename(archive.path(), &unavailable).unwrap();
+        fs::write(ssd.path().join("north/600.jpg"), b"").unwrap();
+        index.rescan();
+        fs::rename(&unavailable, archive.path()).unwrap();
+        assert_eq!(
+            timestamps(&index.get_range("garden", at(0), at(1000))),
+            vec![100, 150, 200, 300, 400, 500, 600]
+        );
+    }
+
+    #[test]
+    fn test_date_partitioned_folders() {
+        let local = |day: u32, hour: u32| Utc.with_ymd_and_hms(2024, 5, day, hour, 0, 0).unwrap();
+        let (first, second) = (local(1, 12).timestamp(), local(2, 12).timestamp());
+
+        let root = tempfile::tempdir().unwrap();
+        let folder = root.path().join("cam1");
+        for (day, timestamp) in [("2024/05/01", first), ("2024/05/02", second)] {
+            fs::create_dir_all(folder.join(day)).unwrap();
+            fs::write(folder.join(day).join(format!("{}.jpg", timestamp)), b"").unwrap();
+        }
+        fs::write(folder.join(format!("{}.jpg", first + 1)), b"").unwrap();
+
+        let mut config = Config::default();
+        config.folders.insert(
+            "cam1".to_string(),
+            FolderConfig {
+                layout: Some("%Y/%m/%d".parse().unwrap()),
+                ..Default::default()
+            },
+        );
+        let index = FrameIndex::new(
+            vec![root.path().to_path_buf()],
+            Arc::new(config),
+            None,
+            None,
+        );
+        let all = || timestamps(&index.get_range("cam1", local(1, 0), local(3, 0)));
+        assert_eq!(all(), vec![first, second]);
+
+        let added = folder.join(format!("2024/05/02/{}.jpg", second + 60));
+        fs::write(&added, b"").unwrap();
+        index.refresh_path(&added);
+        index.refresh_path(&folder.join(format!("{}.jpg", first + 1)));
+        assert_eq!(all(), vec![first, second, second + 60]);
+
+        // Partitions before the rescan window aren't listed again, so their
+        // frames stay in the index
+        fs::remove_file(folder.join(format!("2024/05/01/{}.jpg", first))).unwrap();
+        fs::remove_file(folder.join(format!("2024/05/02/{}.jpg", second))).unwrap();
+        index.scan(Some(local(2, 0)));
+        assert_eq!(all(), vec![first, second + 60]);
+    }
+
+    #[test]
+    fn test_new_partitioned_folders_are_listed_in_full_by_partial_rescans() {
+        let local = |day: u32, hour: u32| Utc.with_ymd_and_hms(2024, 5, day, hour, 0, 0).unwrap();
+        let (first, second) = (local(1, 12).timestamp(), local(2, 12).timestamp());
+
+        let root = tempfile::tempdir().unwrap();
+        let mut config = Config::default();
+        config.folders.insert(
+            "cam1".to_string(),
+            FolderConfig {
+                layout: Some("%Y/%m/%d".parse().unwrap()),
+                ..Default::default()
+            },
+        );
+        let index = FrameIndex::new(
+            vec![root.path().to_path_buf()],
+            Arc::new(config),
+            None,
+            None,
+        );
+
+        let folder = root.path().join("cam1");
+        for (day, timestamp) in [("2024/05/01", first), ("2024/05/02", second)] {
+            fs::create_dir_all(folder.join(day)).unwrap();
+            fs::write(folder.join(day).join(format!("{}.jpg", timestamp)), b"").unwrap();
+        }
+
+        // Listed for the requested range only, without being indexed
+        assert_eq!(
+            timestamps(&index.get_range("cam1", local(2, 0), local(3, 0))),
+            vec![second]
+        );
+        assert!(index.folders().is_empty());
+
+        index.scan(Some(local(2, 0)));
+        assert_eq!(
+            timestamps(&index.get_range("cam1", local(1, 0), local(3, 0))),
+            vec![first, second]
+        );
+    }
+
+    #[test]
+    fn test_ingest_writes_into_partition() {
+        let taken = Utc.with_ymd_and_hms(2024, 5, 1, 12, 0, 0).unwrap();
+        let root = tempfile::tempdir().unwrap();
+        let mut config = Config::default();
+        config.folders.insert(
+            "cam1".to_string(),
+            FolderConfig {
+                layout: Some("%Y/%m/%d".parse().unwrap()),
+                ..Default::default()
+            },
+        );
+        config
+            .aliases
+            .insert("garden".to_string(), vec!["cam1".to_string()]);
+        let index = FrameIndex::new(
+            vec![root.path().to_path_buf()],
+            Arc::new(config),
+            None,
+            None,
+        );
+
+        let path = index.ingest("cam1", taken, b"frame").unwrap();
+        assert_eq!(
+            path,
+            root.path()
+                .join(format!("cam1/2024/05/01/{}.jpg", taken.timestamp()))
+        );
+        assert_eq!(fs::read(&path).unwrap(), b"frame");
+        assert_eq!(
+            timestamps(&index.get_range("cam1", at(0), taken + TimeDelta::days(1))),
+            vec![taken.timestamp()]
+        );
+
+        let error = |folder, taken| index.ingest(folder, taken, b"").unwrap_err().kind();
+        assert_eq!(error("cam1", taken), io::ErrorKind::AlreadyExists);
+        assert_eq!(error("garden", taken), io::ErrorKind::InvalidInput);
+        assert_eq!(error("..", taken), io::ErrorKind::InvalidInput);
+        assert_eq!(fs::read(&path).unwrap(), b"frame");
+    }
+
+    #[test]
+    fn test_catalog_survives_restart() {
+        let root = tempfile::tempdir().unwrap();
+        let folder = root.path().join("cam1");
+        fs::create_dir(&folder).unwrap();
+        for timestamp in [100, 200] {
+            fs::write(folder.join(format!("{}.jpg", timestamp)), b"").unwrap();
+        }
+        let db = root.path().join("catalog.sqlite");
+
+        let catalog = Arc::new(Catalog::open(&db).unwrap());
+        FrameIndex::new(
+            vec![root.path().to_path_buf()],
+            Arc::default(),
+            Some(catalog),
+            None,
+        );
+
+        // Seeded from the catalog, before the background rescan notices the
+        // frame that is gone
+        fs::remove_file(folder.join("200.jpg")).unwrap();
+        let catalog = Arc::new(Catalog::open(&db).unwrap());
+        let index = FrameIndex::new(
+            vec![root.path().to_path_buf()],
+            Arc::default(),
+            Some(catalog.clone()),
+            None,
+        );
+        assert_eq!(index.folders(), vec!["cam1"]);
+
+        index.rescan();
+        assert_eq!(
+            timestamps(&index.get_range("cam1", at(0), at(1000))),
+            vec![100]
+        );
+        assert_eq!(catalog.load().unwrap()[folder.to_str().unwrap()].len(), 1);
+    }
+
+    #[test]
+    fn test_watcher_picks_up_new_frames() {
+        let root = tempfile::tempdir().unwrap();
+        let folder = root.path().join("cam1");
+        fs::create_dir(&folder).unwrap();
+        let index = FrameIndex::new(vec![root.path().to_path_buf()], Arc::default(), None, None);
+        index.watch().unwrap();
+
+        fs::write(folder.join("100.jpg"), b"").unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while index.get_range("cam1", at(0), at(1000)).frames.is_empty() {
+            assert!(Instant::now() < deadline, "watcher never saw the new frame");
+            thread::sleep(Duration::from_millis(20));
+        }
+    }
+}
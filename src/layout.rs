@@ -0,0 +1,233 @@
+use chrono::format::{parse, Item, Parsed, StrftimeItems};
+use chrono::{DateTime, Months, NaiveDate, NaiveDateTime, TimeDelta, TimeZone, Utc};
+use chrono_tz::Tz;
+use serde::Deserialize;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// How frames are partitioned into date folders below a camera folder, as a
+/// strftime-style template with one level per directory, e.g. `%Y/%m/%d` for
+/// `camera/2024/05/01/<ts>.jpg`.
+///
+/// Partition dates are in the folder's time zone. Directories that don't
+/// match the template are ignored.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Layout {
+    levels: Vec<String>,
+}
+
+impl FromStr for Layout {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let levels: Vec<String> = s
+            .trim()
+            .trim_matches('/')
+            .split('/')
+            .map(str::to_string)
+            .collect();
+        for level in &levels {
+            if level.is_empty() || StrftimeItems::new(level).any(|item| item == Item::Error) {
+                return Err(format!("invalid layout: {}", s));
+            }
+        }
+        Ok(Layout { levels })
+    }
+}
+
+impl<'de> Deserialize<'de> for Layout {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let s: String = Deserialize::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+impl Layout {
+    /// How many directories deep frames are below the camera folder.
+    pub fn depth(&self) -> usize {
+        self.levels.len()
+    }
+
+    /// The partition below the camera folder that a frame taken at `time`
+    /// belongs in, with dates in `tz`.
+    pub fn partition(&self, time: DateTime<Utc>, tz: Tz) -> PathBuf {
+        let local = time.with_timezone(&tz);
+        self.levels
+            .iter()
+            .map(|level| local.format(level).to_string())
+            .collect()
+    }
+
+    /// Every file in the partitions below `folder` that overlap `start` to
+    /// `end`, either of which may be open. Other partitions aren't listed.
+    pub fn frame_paths(
+        &self,
+        folder: &Path,
+        tz: Tz,
+        start: Option<DateTime<Utc>>,
+        end: Option<DateTime<Utc>>,
+    ) -> io::Result<Vec<PathBuf>> {
+        let mut paths = Vec::new();
+        self.walk(folder, 0, &Parsed::new(), tz, (start, end), &mut paths)?;
+        Ok(paths)
+    }
+
+    fn walk(
+        &self,
+        dir: &Path,
+        level: usize,
+        parsed: &Parsed,
+        tz: Tz,
+        range: (Option<DateTime<Utc>>, Option<DateTime<Utc>>),
+        paths: &mut Vec<PathBuf>,
+    ) -> io::Result<()> {
+        let entries = fs::read_dir(dir)?.collect::<io::Result<Vec<_>>>()?;
+
+        if level == self.levels.len() {
+            paths.extend(
+                entries
+                    .into_iter()
+                    .filter(|entry| entry.file_type().is_ok_and(|t| t.is_file()))
+                    .map(|entry| entry.path()),
+            );
+            return Ok(());
+        }
+
+        for entry in entries {
+            if !entry.file_type().is_ok_and(|t| t.is_dir()) {
+                continue;
+            }
+            let Some(name) = entry.file_name().to_str().map(str::to_string) else {
+                continue;
+            };
+            let mut parsed = parsed.clone();
+            if parse(&mut parsed, &name, StrftimeItems::new(&self.levels[level])).is_err() {
+                continue;
+            }
+            if let Some((from, to)) = partition_span(&parsed, tz) {
+                let (start, end) = range;
+                if start.is_some_and(|start| to <= start) || end.is_some_and(|end| from >= end) {
+                    continue;
+                }
+            }
+            self.walk(&entry.path(), level + 1, &parsed, tz, range, paths)?;
+        }
+        Ok(())
+    }
+}
+
+/// The period covered by a partition, from the date fields parsed from its
+/// path so far. `None` if the year isn't known yet.
+fn partition_span(parsed: &Parsed, tz: Tz) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+    let year = parsed.year()?;
+    let hour = match (parsed.hour_div_12(), parsed.hour_mod_12()) {
+        (Some(div), Some(modulo)) => Some(div * 12 + modulo),
+        _ => None,
+    };
+
+    let start =
+        NaiveDate::from_ymd_opt(year, parsed.month().unwrap_or(1), parsed.day().unwrap_or(1))?
+            .and_hms_opt(hour.unwrap_or(0), 0, 0)?;
+    let end = if hour.is_some() {
+        start + TimeDelta::hours(1)
+    } else if parsed.day().is_some() {
+        start + TimeDelta::days(1)
+    } else if parsed.month().is_some() {
+        start.checked_add_months(Months::new(1))?
+    } else {
+        start.checked_add_months(Months::new(12))?
+    };
+
+    Some((local_to_utc(start, tz, true), local_to_utc(end, tz, false)))
+}
+
+/// Wall-clock time in `tz` to UTC, taking the widest reading of times that
+/// are ambiguous or skipped around daylight saving changes.
+fn local_to_utc(time: NaiveDateTime, tz: Tz, earliest: bool) -> DateTime<Utc> {
+    let local = tz.from_local_datetime(&time);
+    let resolved = if earliest {
+        local.earliest()
+    } else {
+        local.latest()
+    };
+    match resolved {
+        Some(resolved) => resolved.with_timezone(&Utc),
+        // Skipped by a daylight saving change, so allow for any offset
+        None if earliest => Utc.from_utc_datetime(&time) - TimeDelta::days(1),
+        None => Utc.from_utc_datetime(&time) + TimeDelta::days(1),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TZ: Tz = Tz::America__New_York;
+
+    fn local(date: &str) -> DateTime<Utc> {
+        let time = NaiveDate::parse_from_str(date, "%Y-%m-%d")
+            .unwrap()
+            .and_hms_opt(0, 0, 0)
+            .unwrap();
+        local_to_utc(time, TZ, true)
+    }
+
+    #[test]
+    fn test_parse_layout() {
+        assert_eq!("%Y/%m/%d".parse::<Layout>().unwrap().depth(), 3);
+        assert_eq!("/%Y-%m-%d/".parse::<Layout>().unwrap().depth(), 1);
+        assert!("%Y//%d".parse::<Layout>().is_err());
+        assert!("%Y/%Q".parse::<Layout>().is_err());
+
+        let layout: Layout = "%Y/%m/%d".parse().unwrap();
+        assert_eq!(
+            layout.partition(local("2024-05-01") + TimeDelta::hours(12), TZ),
+            PathBuf::from("2024/05/01")
+        );
+    }
+
+    #[test]
+    fn test_frame_paths_prunes_partitions_outside_range() {
+        let root = tempfile::tempdir().unwrap();
+        for day in ["2024/04/30", "2024/05/01", "2024/05/02", "2023/12/31"] {
+            let dir = root.path().join(day);
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join("1.jpg"), b"").unwrap();
+        }
+        fs::create_dir_all(root.path().join("thumbnails/05/01")).unwrap();
+        fs::write(root.path().join("thumbnails/05/01/1.jpg"), b"").unwrap();
+
+        let layout: Layout = "%Y/%m/%d".parse().unwrap();
+        let found = |start, end| {
+            let mut days: Vec<String> = layout
+                .frame_paths(root.path(), TZ, start, end)
+                .unwrap()
+                .iter()
+                .map(|path| {
+                    let partition = path.parent().unwrap().strip_prefix(root.path()).unwrap();
+                    partition.to_str().unwrap().to_string()
+                })
+                .collect();
+            days.sort();
+            days
+        };
+
+        assert_eq!(
+            found(None, None),
+            vec!["2023/12/31", "2024/04/30", "2024/05/01", "2024/05/02"]
+        );
+        assert_eq!(
+            found(Some(local("2024-05-01")), Some(local("2024-05-02"))),
+            vec!["2024/05/01"]
+        );
+        assert_eq!(
+            found(Some(local("2024-01-15")), None),
+            vec!["2024/04/30", "2024/05/01", "2024/05/02"]
+        );
+    }
+}
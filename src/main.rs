@@ -1,18 +1,48 @@
-use chrono::{DateTime, Utc};
+#![allow(clippy::result_large_err)]
+
+mod activity;
+mod analysis;
+mod capture;
+mod catalog;
+mod composite;
+mod config;
+mod geometry;
+mod index;
+mod layout;
+mod naming;
+mod solar;
+mod storage;
+
+use activity::{ActivityInterval, RegionsOfInterest};
+use analysis::AnalysisCache;
+use capture::{CameraStatus, Capture};
+use catalog::Catalog;
+use chrono::{DateTime, NaiveDate, NaiveTime, SubsecRound, TimeZone, Utc};
+use composite::{Orientation, StackMode};
+use config::{Config, FolderConfig};
+use geometry::{Crop, Geometry, Size};
+use index::FrameIndex;
 use maud::{html, Markup};
+use naming::{FrameFormat, FrameNaming, TimestampCache, TimestampUnit};
 use poem::http::{self, HeaderMap, HeaderValue, StatusCode};
 use poem::listener::TcpListener;
-use poem::web::{Data, Path, Query};
+use poem::middleware::SizeLimit;
+use poem::web::{Data, Json, Path, Query};
 use poem::IntoResponse;
-use poem::{get, handler, EndpointExt, Route, Server};
+use poem::{get, handler, post, EndpointExt, Route, Server};
 use serde::Deserialize;
-use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fmt::{self, Display, Formatter};
-use std::io::Write;
+use std::hash::{Hash, Hasher};
+use std::io::{self, Write};
+use std::num::NonZeroUsize;
 use std::path::PathBuf;
 use std::process::{Command, Stdio};
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use std::{env, fs};
+use storage::ObjectStore;
 use tempfile::NamedTempFile;
 use zip::write::FileOptions;
 
@@ -21,14 +51,22 @@ struct CacheKey {
     first_frame: String,
     start: String,
     end: String,
+    frame_digest: u64,
     fps: usize,
     args_override: Option<Vec<String>>,
+    filters: Vec<String>,
+    stabilize: Option<usize>,
 }
 
 struct VideoCache {
     cache: HashMap<CacheKey, Vec<u8>>,
     keys: Vec<CacheKey>,
     size: usize,
+    /// Stabilization motion analysis, keyed by frame digest. This only depends
+    /// on the frames, so it is reused when the same range is rendered again
+    /// at a different fps or with different ffmpeg arguments.
+    transforms: HashMap<u64, NamedTempFile>,
+    transform_keys: Vec<u64>,
 }
 
 impl VideoCache {
@@ -37,9 +75,23 @@ impl VideoCache {
             cache: HashMap::new(),
             keys: Vec::new(),
             size,
+            transforms: HashMap::new(),
+            transform_keys: Vec::new(),
         }
     }
 
+    fn get_transforms(&self, frame_digest: u64) -> Option<&std::path::Path> {
+        self.transforms.get(&frame_digest).map(|file| file.path())
+    }
+
+    fn set_transforms(&mut self, frame_digest: u64, file: NamedTempFile) {
+        if self.transforms.len() >= self.size {
+            self.transforms.remove(&self.transform_keys.remove(0));
+        }
+        self.transforms.insert(frame_digest, file);
+        self.transform_keys.push(frame_digest);
+    }
+
     fn get(&self, key: &CacheKey) -> Option<&Vec<u8>> {
         self.cache.get(key)
     }
@@ -74,6 +126,57 @@ impl From<CommaSeparatedString> for Vec<String> {
     }
 }
 
+/// A duration written like `30s`, `5m`, `2h` or `1d`. A bare number is seconds.
+#[derive(Debug, Clone, Copy)]
+struct HumanDuration(chrono::Duration);
+
+impl HumanDuration {
+    fn parse(s: &str) -> Option<Self> {
+        let s = s.trim();
+        let (value, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+            Some(i) => s.split_at(i),
+            None => (s, "s"),
+        };
+        let value: i64 = value.parse().ok()?;
+        let duration = match unit {
+            "s" => chrono::Duration::seconds(value),
+            "m" => chrono::Duration::minutes(value),
+            "h" => chrono::Duration::hours(value),
+            "d" => chrono::Duration::days(value),
+            _ => return None,
+        };
+        Some(HumanDuration(duration))
+    }
+}
+
+impl<'de> Deserialize<'de> for HumanDuration {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let s: String = Deserialize::deserialize(deserializer)?;
+        HumanDuration::parse(&s)
+            .ok_or_else(|| serde::de::Error::custom(format!("invalid duration: {}", s)))
+    }
+}
+
+/// A time of day in the folder's time zone, written like `12:00` or `07:30:15`.
+#[derive(Debug, Clone, Copy)]
+struct TimeOfDay(NaiveTime);
+
+impl<'de> Deserialize<'de> for TimeOfDay {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let s: String = Deserialize::deserialize(deserializer)?;
+        NaiveTime::parse_from_str(&s, "%H:%M")
+            .or_else(|_| NaiveTime::parse_from_str(&s, "%H:%M:%S"))
+            .map(TimeOfDay)
+            .map_err(|_| serde::de::Error::custom(format!("invalid time of day: {}", s)))
+    }
+}
+
 fn handle_range_requests(
     data: Vec<u8>,
     is_cache_hit: bool,
@@ -134,83 +237,703 @@ fn handle_range_requests(
     }
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Default)]
 struct QueryParams {
     fps: Option<usize>,
     ffmpeg_args: Option<CommaSeparatedString>,
     format: Option<String>,
+    /// Keep only every Nth frame.
+    every: Option<NonZeroUsize>,
+    /// Keep at most one frame per interval, the one closest to the start of it.
+    interval: Option<HumanDuration>,
+    /// Keep at most this many frames, evenly spaced across the range.
+    limit: Option<NonZeroUsize>,
+    /// Keep one frame per day, the one closest to this time of day in the
+    /// folder's time zone.
+    time_of_day: Option<TimeOfDay>,
+    /// With `time_of_day`, skip days with no frame within this distance of it.
+    time_window: Option<HumanDuration>,
+    /// Drop frames taken between sunset and sunrise at the folder's location.
+    daylight: Option<bool>,
+    /// With `daylight`, also keep frames taken during civil twilight.
+    twilight: Option<bool>,
+    /// Drop frames whose mean luminance (0-255) is below this.
+    min_brightness: Option<f32>,
+    /// Drop frames whose mean luminance (0-255) is above this.
+    max_brightness: Option<f32>,
+    /// Drop frames whose luminance standard deviation is below this.
+    min_contrast: Option<f32>,
+    /// Smooth brightness changes over a window of this many frames.
+    deflicker: Option<usize>,
+    /// Remove camera shake with a motion analysis pass before rendering.
+    stabilize: Option<bool>,
+    /// With `stabilize`, how many frames either side to smooth camera motion over.
+    stabilize_smoothing: Option<usize>,
+    /// Crop each frame to `x,y,w,h`.
+    crop: Option<Crop>,
+    /// Rotate each frame clockwise by 90, 180 or 270 degrees.
+    rotate: Option<u32>,
+    /// Scale each frame to `WxH`.
+    scale: Option<Size>,
+    /// Scale each frame to this width, keeping the aspect ratio.
+    width: Option<u32>,
+    /// Synthesize in-between frames to reach `output_fps`.
+    interpolate: Option<Interpolation>,
+    /// With `interpolate`, the frame rate of the rendered video. Defaults to 60.
+    output_fps: Option<usize>,
+    /// Drop frames that look the same as the previous frame kept.
+    dedup: Option<bool>,
+    /// With `dedup`, frames whose 64-bit perceptual hashes differ by at most
+    /// this many bits are duplicates. Defaults to 3.
+    dedup_threshold: Option<u32>,
+    /// Only count activity inside these regions, as `x,y,w,h` fractions of
+    /// the frame separated by `;`.
+    roi: Option<RegionsOfInterest>,
+    /// Fraction of the frame that must change between frames to count as
+    /// activity. Defaults to 0.05.
+    activity_threshold: Option<f32>,
+    /// Active frames closer together than this are merged into one interval.
+    /// Defaults to 5 minutes.
+    activity_gap: Option<HumanDuration>,
+    /// Only keep frames from periods of activity, skipping idle stretches.
+    highlights: Option<bool>,
+    /// With `highlights`, also keep frames this long before and after each
+    /// period of activity.
+    highlight_padding: Option<HumanDuration>,
+    /// Slow down busy parts of the timelapse. Only `activity` is supported.
+    ramp: Option<String>,
+    /// Slow down these time of day ranges in the folder's time zone, like
+    /// `14:00-15:00,18:00-18:30`.
+    slow: Option<TimeRanges>,
+    /// How many times slower slowed down frames play. Defaults to 4.
+    slow_factor: Option<f32>,
+    /// With `format=stack`, how frames are combined. Defaults to max.
+    mode: Option<StackMode>,
+    /// With `format=timeslice`, whether slices are columns (vertical) or rows
+    /// (horizontal). Defaults to vertical.
+    orientation: Option<Orientation>,
+    /// With `format=timeslice`, crossfade between neighbouring slices.
+    blend: Option<bool>,
+}
+
+/// Comma separated time of day ranges, like `14:00-15:00,22:00-02:00`.
+/// A range that ends before it starts wraps past midnight.
+#[derive(Debug, Clone)]
+struct TimeRanges(Vec<(NaiveTime, NaiveTime)>);
+
+impl TimeRanges {
+    fn parse(s: &str) -> Option<Self> {
+        s.split(',')
+            .map(|range| {
+                let (start, end) = range.split_once('-')?;
+                let start = NaiveTime::parse_from_str(start.trim(), "%H:%M").ok()?;
+                let end = NaiveTime::parse_from_str(end.trim(), "%H:%M").ok()?;
+                Some((start, end))
+            })
+            .collect::<Option<_>>()
+            .map(TimeRanges)
+    }
+
+    fn contains(&self, time: NaiveTime) -> bool {
+        self.0.iter().any(|&(start, end)| {
+            if start <= end {
+                time >= start && time < end
+            } else {
+                time >= start || time < end
+            }
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for TimeRanges {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let s: String = Deserialize::deserialize(deserializer)?;
+        TimeRanges::parse(&s)
+            .ok_or_else(|| serde::de::Error::custom(format!("invalid time ranges: {}", s)))
+    }
+}
+
+/// How in-between frames are synthesized for smooth slow timelapses.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Interpolation {
+    /// Motion-compensated interpolation. Smoothest, but slow to render.
+    Mci,
+    /// Crossfade between neighbouring frames.
+    Blend,
+}
+
+impl QueryParams {
+    /// The crop, rotation and scaling for this request, falling back to the
+    /// folder's configured defaults.
+    fn geometry(&self, folder_config: &FolderConfig) -> poem::Result<Geometry> {
+        let geometry = Geometry {
+            crop: self.crop,
+            rotate: self.rotate,
+            scale: self.scale,
+            width: self.width,
+        }
+        .or_folder_defaults(folder_config);
+
+        geometry
+            .validate()
+            .map_err(|e| poem::Error::from_string(e, StatusCode::BAD_REQUEST))?;
+        Ok(geometry)
+    }
+}
+
+/// Builds the ffmpeg filter chain for the typed video processing parameters.
+/// These are ignored when `ffmpeg_args` overrides the whole command line.
+fn video_filters(params: &QueryParams, folder_config: &FolderConfig) -> poem::Result<Vec<String>> {
+    let mut filters = params.geometry(folder_config)?.ffmpeg_filters();
+
+    if let Some(size) = params.deflicker {
+        if !(2..=129).contains(&size) {
+            return Err(poem::Error::from_string(
+                "deflicker must be between 2 and 129 frames",
+                StatusCode::BAD_REQUEST,
+            ));
+        }
+        filters.push(format!("deflicker=size={}", size));
+    }
+
+    if let Some(interpolation) = params.interpolate {
+        let output_fps = params.output_fps.unwrap_or(60);
+        if output_fps == 0 {
+            return Err(poem::Error::from_string(
+                "output_fps must be greater than zero",
+                StatusCode::BAD_REQUEST,
+            ));
+        }
+        filters.push(match interpolation {
+            Interpolation::Mci => format!(
+                "minterpolate=fps={}:mi_mode=mci:mc_mode=aobmc:me_mode=bidir:vsbmc=1",
+                output_fps
+            ),
+            Interpolation::Blend => format!("framerate=fps={}", output_fps),
+        });
+    }
+
+    Ok(filters)
 }
 
 #[derive(Debug, Clone)]
 struct Frame {
     path: PathBuf,
     timestamp: i64,
+    /// Sub-second part of the timestamp, for names that have one. Only used
+    /// to order frames taken within the same second.
+    subsec_nanos: u32,
+    /// How many frame periods this frame stays on screen, 1.0 unless the
+    /// request slows down part of the timelapse.
+    hold: f32,
+}
+
+impl Frame {
+    /// Recognizes frame files, which are JPEG, PNG or WebP images named
+    /// `<unix timestamp>.<extension>` unless the folder is configured
+    /// otherwise.
+    fn from_path(path: PathBuf, naming: &FrameNaming, timestamps: &TimestampCache) -> Option<Self> {
+        let (timestamp, subsec_nanos) = naming.frame_timestamp(&path, timestamps)?;
+
+        Some(Frame {
+            path,
+            timestamp,
+            subsec_nanos,
+            hold: 1.0,
+        })
+    }
+
+    fn sort_key(&self) -> (i64, u32, &PathBuf) {
+        (self.timestamp, self.subsec_nanos, &self.path)
+    }
 }
 
-#[derive(Debug)]
+/// Frames sorted by timestamp.
+#[derive(Debug, Default)]
 struct FrameCollection {
     frames: Vec<Frame>,
 }
 
 impl FrameCollection {
-    fn new(folder: PathBuf) -> Self {
-        let read_dir = fs::read_dir(&folder).unwrap();
-
-        let frames: Vec<Frame> = read_dir
-            .filter_map(|entry| {
-                let entry = entry.unwrap();
-                let file_name = entry.file_name().into_string().unwrap();
-                let file_name_without_extension = file_name.trim_end_matches(".jpg"); // Adjust the extension if needed
-                let timestamp: Result<i64, _> = file_name_without_extension.parse();
-
-                match timestamp {
-                    Ok(timestamp) => Some(Frame {
-                        path: entry.path(),
-                        timestamp,
-                    }),
-                    Err(_) => None,
+    /// Lists the frames in `folder` from `since` on. With a date-partitioned
+    /// layout, only partitions that may hold frames from `since` to `until`
+    /// are listed.
+    fn scan(
+        folder: PathBuf,
+        folder_config: &FolderConfig,
+        since: Option<DateTime<Utc>>,
+        until: Option<DateTime<Utc>>,
+        timestamps: &TimestampCache,
+    ) -> io::Result<Self> {
+        let paths: Vec<PathBuf> = match &folder_config.layout {
+            Some(layout) => layout.frame_paths(&folder, folder_config.timezone(), since, until)?,
+            None => fs::read_dir(&folder)?
+                .map(|entry| entry.map(|entry| entry.path()))
+                .collect::<io::Result<_>>()?,
+        };
+
+        let mut frames: Vec<Frame> = paths
+            .into_iter()
+            .filter_map(|path| Frame::from_path(path, &folder_config.naming, timestamps))
+            .filter(|frame| since.is_none_or(|since| frame.timestamp >= since.timestamp()))
+            .collect();
+        frames.sort_by(|a, b| a.sort_key().cmp(&b.sort_key()));
+
+        Ok(FrameCollection { frames })
+    }
+
+    /// Adds a frame, keeping the collection sorted. Returns false if the
+    /// frame was already present.
+    fn insert(&mut self, frame: Frame) -> bool {
+        match self
+            .frames
+            .binary_search_by(|f| f.sort_key().cmp(&frame.sort_key()))
+        {
+            Ok(_) => false,
+            Err(position) => {
+                self.frames.insert(position, frame);
+                true
+            }
+        }
+    }
+
+    /// Combines collections in order of precedence. Where more than one has
+    /// a frame with the same timestamp, only the first one's is kept.
+    fn merge(mut collections: Vec<FrameCollection>) -> Self {
+        collections.retain(|collection| !collection.frames.is_empty());
+        if collections.len() <= 1 {
+            return collections.pop().unwrap_or_default();
+        }
+
+        let mut frames: Vec<(usize, Frame)> = collections
+            .into_iter()
+            .enumerate()
+            .flat_map(|(i, collection)| collection.frames.into_iter().map(move |f| (i, f)))
+            .collect();
+        frames.sort_by(|(a_source, a), (b_source, b)| {
+            (a.timestamp, a.subsec_nanos, a_source).cmp(&(b.timestamp, b.subsec_nanos, b_source))
+        });
+        frames.dedup_by_key(|(_, frame)| (frame.timestamp, frame.subsec_nanos));
+
+        FrameCollection {
+            frames: frames.into_iter().map(|(_, frame)| frame).collect(),
+        }
+    }
+
+    /// Removes the frame stored at `path`, if there is one.
+    fn remove(&mut self, path: &std::path::Path) -> bool {
+        let before = self.frames.len();
+        self.frames.retain(|frame| frame.path != path);
+        self.frames.len() != before
+    }
+
+    fn get_range(&self, start: DateTime<Utc>, end: DateTime<Utc>) -> Self {
+        let first = self
+            .frames
+            .partition_point(|frame| frame.timestamp <= start.timestamp());
+        let last = self
+            .frames
+            .partition_point(|frame| frame.timestamp < end.timestamp());
+        let frames: Vec<Frame> = self.frames[first..last.max(first)].to_vec();
+
+        FrameCollection { frames }
+    }
+
+    /// Keeps only frames taken while the sun was above `min_elevation` degrees
+    /// at the given location.
+    fn daylight_only(self, latitude: f64, longitude: f64, min_elevation: f64) -> Self {
+        let frames = self
+            .frames
+            .into_iter()
+            .filter(|frame| {
+                let Some(time) = DateTime::from_timestamp(frame.timestamp, 0) else {
+                    return false;
+                };
+                solar::solar_elevation(latitude, longitude, time) > min_elevation
+            })
+            .collect();
+
+        FrameCollection { frames }
+    }
+
+    /// Drops empty, truncated, corrupt or still-uploading frames, returning
+    /// the remaining frames and how many were dropped.
+    fn validated(self, analysis: &Mutex<AnalysisCache>, min_age: Duration) -> (Self, usize) {
+        let paths: Vec<&std::path::Path> = self.frames.iter().map(|f| f.path.as_path()).collect();
+        let valid = analysis::validate_frames(analysis, &paths, min_age);
+
+        let total = self.frames.len();
+        let frames: Vec<Frame> = self
+            .frames
+            .into_iter()
+            .zip(valid)
+            .filter_map(|(frame, valid)| valid.then_some(frame))
+            .collect();
+        let skipped = total - frames.len();
+
+        if skipped > 0 {
+            println!("Skipped {} invalid frames", skipped);
+        }
+
+        (FrameCollection { frames }, skipped)
+    }
+
+    /// Drops frames that are too dark, too bright or too flat according to
+    /// the `min_brightness`, `max_brightness` and `min_contrast` parameters.
+    /// Frames that can't be decoded are dropped as well.
+    fn within_brightness(self, params: &QueryParams, analysis: &Mutex<AnalysisCache>) -> Self {
+        if params.min_brightness.is_none()
+            && params.max_brightness.is_none()
+            && params.min_contrast.is_none()
+        {
+            return self;
+        }
+
+        let paths: Vec<&std::path::Path> = self.frames.iter().map(|f| f.path.as_path()).collect();
+        let stats = analysis::frame_stats(analysis, &paths);
+
+        let frames = self
+            .frames
+            .into_iter()
+            .zip(stats)
+            .filter_map(|(frame, stats)| {
+                let stats = stats?;
+                let keep = params
+                    .min_brightness
+                    .is_none_or(|min| stats.luminance >= min)
+                    && params
+                        .max_brightness
+                        .is_none_or(|max| stats.luminance <= max)
+                    && params.min_contrast.is_none_or(|min| stats.contrast >= min);
+                keep.then_some(frame)
+            })
+            .collect();
+
+        FrameCollection { frames }
+    }
+
+    /// Drops frames whose perceptual hash is within `threshold` bits of the
+    /// last frame kept, collapsing runs of a static scene down to one frame.
+    fn without_duplicates(self, threshold: u32, analysis: &Mutex<AnalysisCache>) -> Self {
+        let paths: Vec<&std::path::Path> = self.frames.iter().map(|f| f.path.as_path()).collect();
+        let stats = analysis::frame_stats(analysis, &paths);
+
+        let total = self.frames.len();
+        let mut last_kept: Option<analysis::FrameStats> = None;
+        let frames: Vec<Frame> = self
+            .frames
+            .into_iter()
+            .zip(stats)
+            .filter(|(_, stats)| {
+                // Keep anything that couldn't be hashed rather than guess
+                let Some(stats) = stats else {
+                    return true;
+                };
+                if last_kept.is_some_and(|last| last.distance(stats) <= threshold) {
+                    return false;
                 }
+                last_kept = Some(*stats);
+                true
             })
+            .map(|(frame, _)| frame)
             .collect();
 
+        println!("Dropped {} duplicate frames", total - frames.len());
+
         FrameCollection { frames }
     }
 
-    fn get_range(&self, start: DateTime<Utc>, end: DateTime<Utc>) -> Self {
-        let mut frames: Vec<Frame> = self
+    /// Finds the intervals where consecutive frames change significantly.
+    fn activity(
+        &self,
+        params: &QueryParams,
+        analysis: &Mutex<AnalysisCache>,
+    ) -> Vec<ActivityInterval> {
+        let paths: Vec<&std::path::Path> = self.frames.iter().map(|f| f.path.as_path()).collect();
+        let stats = analysis::frame_stats(analysis, &paths);
+        let scores = activity::change_scores(&stats, params.roi.as_ref());
+        let timestamps: Vec<i64> = self.frames.iter().map(|f| f.timestamp).collect();
+
+        activity::intervals(
+            &timestamps,
+            &scores,
+            params.activity_threshold.unwrap_or(0.05),
+            params
+                .activity_gap
+                .map_or(300, |HumanDuration(gap)| gap.num_seconds()),
+        )
+    }
+
+    /// Keeps only the frames within `padding` seconds of a period of activity.
+    fn highlights(
+        self,
+        params: &QueryParams,
+        analysis: &Mutex<AnalysisCache>,
+        padding: i64,
+    ) -> Self {
+        let intervals = self.activity(params, analysis);
+        println!("Found {} periods of activity", intervals.len());
+
+        let frames = self
             .frames
-            .iter()
+            .into_iter()
             .filter(|frame| {
-                frame.timestamp > start.timestamp() && frame.timestamp < end.timestamp()
-            }).cloned()
+                intervals.iter().any(|interval| {
+                    frame.timestamp >= interval.first_frame - padding
+                        && frame.timestamp <= interval.last_frame + padding
+                })
+            })
             .collect();
 
-        println!(
-            "Found {} frames between {} and {}",
-            frames.len(),
-            start.format("%Y-%m-%d %H:%M:%S UTC"),
-            end.format("%Y-%m-%d %H:%M:%S UTC")
-        );
-        frames.sort_by_key(|a| a.timestamp);
+        FrameCollection { frames }
+    }
+
+    /// Sets how long each frame stays on screen. Frames in the `slow` time
+    /// ranges of `tz` are held `slow_factor` times longer, and with
+    /// `ramp=activity` frames are held longer the more the scene changed,
+    /// up to `slow_factor` for frames at the activity threshold.
+    fn ramped<Tz: TimeZone>(
+        mut self,
+        params: &QueryParams,
+        tz: &Tz,
+        analysis: &Mutex<AnalysisCache>,
+    ) -> poem::Result<Self> {
+        let slow_factor = params.slow_factor.unwrap_or(4.0);
+        if !(slow_factor.is_finite() && slow_factor > 0.0) {
+            return Err(poem::Error::from_string(
+                "slow_factor must be a positive number",
+                StatusCode::BAD_REQUEST,
+            ));
+        }
+
+        if let Some(slow) = &params.slow {
+            for frame in &mut self.frames {
+                let Some(local) = tz.timestamp_opt(frame.timestamp, 0).earliest() else {
+                    continue;
+                };
+                if slow.contains(local.time()) {
+                    frame.hold = slow_factor;
+                }
+            }
+        }
+
+        match params.ramp.as_deref() {
+            None => {}
+            Some("activity") => {
+                let paths: Vec<&std::path::Path> =
+                    self.frames.iter().map(|f| f.path.as_path()).collect();
+                let stats = analysis::frame_stats(analysis, &paths);
+                let scores = activity::change_scores(&stats, params.roi.as_ref());
+                let threshold = params.activity_threshold.unwrap_or(0.05);
+                for (frame, score) in self.frames.iter_mut().zip(scores) {
+                    let busyness = (score / threshold).min(1.0);
+                    frame.hold = frame.hold.max(1.0 + (slow_factor - 1.0) * busyness);
+                }
+            }
+            Some(other) => {
+                return Err(poem::Error::from_string(
+                    format!("unknown ramp: {}", other),
+                    StatusCode::BAD_REQUEST,
+                ));
+            }
+        }
+
+        Ok(self)
+    }
+
+    /// Keeps one frame per day in `tz`, the one closest to `target` on that
+    /// day. Days whose closest frame is further than `window` away are dropped.
+    fn at_time_of_day<Tz: TimeZone>(
+        self,
+        tz: &Tz,
+        target: NaiveTime,
+        window: Option<chrono::Duration>,
+    ) -> Self {
+        let mut best: Vec<(NaiveDate, i64, Frame)> = Vec::new();
+
+        for frame in self.frames {
+            let Some(local) = tz.timestamp_opt(frame.timestamp, 0).earliest() else {
+                continue;
+            };
+            let day = local.date_naive();
+            let Some(target_time) = tz.from_local_datetime(&day.and_time(target)).earliest() else {
+                continue;
+            };
+            let distance = (frame.timestamp - target_time.timestamp()).abs();
+
+            if window.is_some_and(|window| distance > window.num_seconds()) {
+                continue;
+            }
+
+            match best.last_mut() {
+                Some((best_day, best_distance, best_frame)) if *best_day == day => {
+                    if distance < *best_distance {
+                        *best_distance = distance;
+                        *best_frame = frame;
+                    }
+                }
+                _ => best.push((day, distance, frame)),
+            }
+        }
+
+        FrameCollection {
+            frames: best.into_iter().map(|(_, _, frame)| frame).collect(),
+        }
+    }
+
+    /// Thins the collection down according to the `every`, `interval` and
+    /// `limit` query parameters, in that order.
+    fn sample(self, params: &QueryParams) -> Self {
+        let mut frames = self.frames;
+
+        if let Some(every) = params.every {
+            frames = frames.into_iter().step_by(every.get()).collect();
+        }
+
+        if let Some(HumanDuration(interval)) = params.interval {
+            let interval = interval.num_seconds();
+            if interval > 0 && !frames.is_empty() {
+                let origin = frames[0].timestamp;
+                let mut kept: Vec<Frame> = Vec::new();
+                let mut current_bucket = None;
+                for frame in frames {
+                    let bucket = (frame.timestamp - origin) / interval;
+                    let offset = (frame.timestamp - origin) % interval;
+                    if current_bucket == Some(bucket) {
+                        let last = kept.last_mut().unwrap();
+                        let last_offset = (last.timestamp - origin) % interval;
+                        if offset < last_offset {
+                            *last = frame;
+                        }
+                    } else {
+                        current_bucket = Some(bucket);
+                        kept.push(frame);
+                    }
+                }
+                frames = kept;
+            }
+        }
+
+        if let Some(limit) = params.limit.map(NonZeroUsize::get) {
+            if frames.len() > limit {
+                // Spread the kept frames so the first and last frame of the range survive
+                let last = frames.len() - 1;
+                frames = (0..limit)
+                    .map(|i| frames[i * last / (limit - 1).max(1)].clone())
+                    .collect();
+            }
+        }
 
         FrameCollection { frames }
     }
 
-    fn get_past_days(&self, days: i64) -> Self {
-        let now = Utc::now();
-        let days_ago = now - chrono::Duration::days(days);
+    /// A digest of every frame, so that differently sampled collections over
+    /// the same range don't share a cache entry.
+    fn digest(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        for frame in &self.frames {
+            frame.path.hash(&mut hasher);
+            frame.timestamp.hash(&mut hasher);
+            frame.hold.to_bits().hash(&mut hasher);
+        }
+        hasher.finish()
+    }
 
-        self.get_range(days_ago, now)
+    /// The ffmpeg concat demuxer script showing each frame for `1/fps`,
+    /// stretched by the frame's hold.
+    fn concat_list(&self, fps: usize) -> String {
+        let mut ffmpeg_input = String::new();
+        for frame in &self.frames {
+            ffmpeg_input.push_str(&format!("file 'file:{}'\n", frame.path.to_str().unwrap()));
+            ffmpeg_input.push_str(&format!("outpoint {:.4}\n", frame.hold / fps as f32));
+        }
+        ffmpeg_input
     }
 
-    fn into_paths(self) -> Vec<PathBuf> {
-        self.frames.into_iter().map(|frame| frame.path).collect()
+    /// FFmpeg's concat demuxer can't switch image codecs partway through, so
+    /// when frames are in more than one format, every frame that isn't a
+    /// JPEG is converted to one in `dir`.
+    fn with_uniform_format(mut self, dir: &std::path::Path) -> Result<Self, String> {
+        let formats: HashSet<Option<FrameFormat>> = self
+            .frames
+            .iter()
+            .map(|frame| FrameFormat::of(&frame.path))
+            .collect();
+        if formats.len() <= 1 {
+            return Ok(self);
+        }
+
+        let mut converted = 0;
+        for (i, frame) in self.frames.iter_mut().enumerate() {
+            if FrameFormat::of(&frame.path) == Some(FrameFormat::Jpeg) {
+                continue;
+            }
+            let image = image::open(&frame.path)
+                .map_err(|e| format!("{}: {}", frame.path.display(), e))?
+                .into_rgb8();
+            let path = dir.join(format!("{}.jpg", i));
+            let file = fs::File::create(&path).map_err(|e| e.to_string())?;
+            image::codecs::jpeg::JpegEncoder::new_with_quality(std::io::BufWriter::new(file), 95)
+                .encode_image(&image)
+                .map_err(|e| e.to_string())?;
+            frame.path = path;
+            converted += 1;
+        }
+        println!("Converted {} frames to JPEG to match the rest", converted);
+        Ok(self)
+    }
+
+    /// Runs the motion analysis pass of stabilization, writing the detected
+    /// camera movement to `transforms` for `vidstabtransform` to undo.
+    fn detect_motion(&self, fps: usize, transforms: &std::path::Path) -> Result<(), String> {
+        let mut child = Command::new("ffmpeg")
+            .args([
+                "-y",
+                "-safe",
+                "0",
+                "-protocol_whitelist",
+                "pipe,file",
+                "-f",
+                "concat",
+                "-i",
+                "pipe:0",
+                "-vf",
+                &format!("vidstabdetect=result={}", transforms.display()),
+                "-f",
+                "null",
+                "-",
+            ])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| e.to_string())?;
+
+        let mut stdin = child.stdin.take().expect("Failed to open stdin");
+        let ffmpeg_input = self.concat_list(fps);
+        std::thread::spawn(move || {
+            stdin
+                .write_all(ffmpeg_input.as_bytes())
+                .expect("Failed to write to stdin");
+        });
+
+        let output = child.wait_with_output().map_err(|e| e.to_string())?;
+        if !output.status.success() {
+            return Err(String::from_utf8_lossy(&output.stderr).into_owned());
+        }
+        Ok(())
     }
 
     fn into_mp4(
         self,
         fps: usize,
         args_override: Option<Vec<String>>,
+        mut filters: Vec<String>,
+        stabilize: Option<usize>,
         cache: &mut VideoCache,
         headers: &HeaderMap,
     ) -> poem::Result<poem::Response> {
@@ -219,13 +942,19 @@ impl FrameCollection {
                 .status(StatusCode::NOT_FOUND)
                 .body(()));
         }
+        // Like the other typed options, stabilization is ignored when the
+        // command line is overridden, so don't analyze motion for it
+        let stabilize = stabilize.filter(|_| args_override.is_none());
 
         let cache_key = CacheKey {
             first_frame: self.frames[0].path.to_str().unwrap().to_string(),
             start: self.frames[0].timestamp.to_string(),
             end: self.frames[self.frames.len() - 1].timestamp.to_string(),
+            frame_digest: self.digest(),
             fps,
             args_override: args_override.clone(),
+            filters: filters.clone(),
+            stabilize,
         };
 
         if let Some(cached) = cache.get(&cache_key) {
@@ -238,12 +967,51 @@ impl FrameCollection {
         }
 
         println!("Cache miss: {:?}", cache_key);
+
+        let converted = tempfile::tempdir().expect("Failed to create temporary directory");
+        let frames = match self.with_uniform_format(converted.path()) {
+            Ok(frames) => frames,
+            Err(e) => {
+                eprintln!("Failed to convert frames for FFmpeg: {}", e);
+                return Ok(poem::Response::builder()
+                    .status(StatusCode::INTERNAL_SERVER_ERROR)
+                    .body("failed to convert frames"));
+            }
+        };
+
+        if let Some(smoothing) = stabilize {
+            if cache.get_transforms(cache_key.frame_digest).is_none() {
+                let transforms = NamedTempFile::new().expect("Failed to create temporary file");
+                if let Err(e) = frames.detect_motion(fps, transforms.path()) {
+                    eprintln!("FFmpeg motion analysis failed: {}", e);
+                    return Ok(poem::Response::builder()
+                        .status(StatusCode::INTERNAL_SERVER_ERROR)
+                        .body("ffmpeg failed to analyze camera motion"));
+                }
+                cache.set_transforms(cache_key.frame_digest, transforms);
+            }
+            let transforms = cache.get_transforms(cache_key.frame_digest).unwrap();
+            filters.insert(
+                0,
+                format!(
+                    "vidstabtransform=input={}:smoothing={}",
+                    transforms.display(),
+                    smoothing
+                ),
+            );
+        }
+
         let temp_file = NamedTempFile::new().expect("Failed to create temporary file");
         let temp_path = temp_file.path().to_str().unwrap().to_string();
 
         let mut child = Command::new("ffmpeg")
             .args(args_override.unwrap_or_else(|| {
-                vec![
+                let filter_args = if filters.is_empty() {
+                    vec![]
+                } else {
+                    vec!["-vf".to_string(), filters.join(",")]
+                };
+                [
                     "-y".to_string(),
                     "-safe".to_string(),
                     "0".to_string(),
@@ -253,6 +1021,10 @@ impl FrameCollection {
                     "concat".to_string(),
                     "-i".to_string(),
                     "pipe:0".to_string(),
+                ]
+                .into_iter()
+                .chain(filter_args)
+                .chain([
                     "-c:v".to_string(),
                     "libx264".to_string(),
                     "-preset".to_string(),
@@ -264,7 +1036,8 @@ impl FrameCollection {
                     "-f".to_string(),
                     "mp4".to_string(),
                     temp_path.to_string(),
-                ]
+                ])
+                .collect()
             }))
             .stdin(Stdio::piped())
             .stdout(Stdio::piped())
@@ -273,11 +1046,7 @@ impl FrameCollection {
             .expect("Failed to spawn child process");
 
         let mut stdin = child.stdin.take().expect("Failed to open stdin");
-        let mut ffmpeg_input = String::new();
-        for path in self.into_paths() {
-            ffmpeg_input.push_str(&format!("file 'file:{}'\n", path.to_str().unwrap()));
-            ffmpeg_input.push_str(&format!("outpoint {:.2}\n", 1f32 / fps as f32));
-        }
+        let ffmpeg_input = frames.concat_list(fps);
 
         std::thread::spawn(move || {
             stdin
@@ -323,6 +1092,45 @@ impl FrameCollection {
         ))
     }
 
+    fn into_stack(self, mode: StackMode, geometry: &Geometry) -> poem::Result<poem::Response> {
+        if self.frames.is_empty() {
+            return Ok(poem::Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(()));
+        }
+
+        let paths: Vec<&std::path::Path> = self.frames.iter().map(|f| f.path.as_path()).collect();
+        let Some(image) = composite::stack(&paths, mode, geometry) else {
+            return Ok(poem::Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body("no frames could be decoded"));
+        };
+
+        into_jpeg_response(image, self.frames.len())
+    }
+
+    fn into_timeslice(
+        self,
+        orientation: Orientation,
+        blend: bool,
+        geometry: &Geometry,
+    ) -> poem::Result<poem::Response> {
+        if self.frames.is_empty() {
+            return Ok(poem::Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(()));
+        }
+
+        let paths: Vec<&std::path::Path> = self.frames.iter().map(|f| f.path.as_path()).collect();
+        let Some(image) = composite::timeslice(&paths, orientation, blend, geometry) else {
+            return Ok(poem::Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body("no frames could be decoded"));
+        };
+
+        into_jpeg_response(image, self.frames.len())
+    }
+
     fn into_zip(mut self) -> poem::Result<poem::Response> {
         if self.frames.is_empty() {
             return Ok(poem::Response::builder()
@@ -336,7 +1144,16 @@ impl FrameCollection {
 
         let frame_count = self.frames.len();
         while let Some(frame) = self.frames.pop() {
-            let file_name = format!("{}.jpg", frame.timestamp);
+            // Frames keep their original format and extension
+            let extension = frame
+                .path
+                .extension()
+                .and_then(|e| e.to_str())
+                .unwrap_or("jpg");
+            let file_name = match frame.subsec_nanos {
+                0 => format!("{}.{}", frame.timestamp, extension),
+                nanos => format!("{}.{:09}.{}", frame.timestamp, nanos, extension),
+            };
             if let Err(e) = zip.start_file(&file_name, options) {
                 eprintln!("Failed to start file in zip: {}", e);
                 return Ok(poem::Response::builder()
@@ -392,21 +1209,196 @@ impl FrameCollection {
             .body(zip_data))
     }
 
+    /// Applies the validation, filtering and sampling parameters of a request,
+    /// returning the selected frames and how many were skipped as invalid.
+    /// With `highlights`, idle stretches are skipped before sampling.
+    fn select(
+        self,
+        params: &QueryParams,
+        folder_config: &FolderConfig,
+        config: &Config,
+        analysis: &Mutex<AnalysisCache>,
+        store: Option<&ObjectStore>,
+        highlights: bool,
+    ) -> poem::Result<(Self, usize)> {
+        let frames = if params.daylight.unwrap_or(false) {
+            let (Some(latitude), Some(longitude)) =
+                (folder_config.latitude, folder_config.longitude)
+            else {
+                return Err(poem::Error::from_string(
+                    "daylight filtering needs a latitude and longitude for this folder",
+                    StatusCode::BAD_REQUEST,
+                ));
+            };
+            let min_elevation = if params.twilight.unwrap_or(false) {
+                solar::CIVIL_TWILIGHT_ELEVATION
+            } else {
+                solar::SUNRISE_ELEVATION
+            };
+            self.daylight_only(latitude, longitude, min_elevation)
+        } else {
+            self
+        };
+
+        // Archived frames are picked by timestamp before they are downloaded,
+        // unless a filter needs the contents of every frame first. A picked
+        // frame that turns out to be invalid is then dropped, not replaced.
+        let by_content = params.min_brightness.is_some()
+            || params.max_brightness.is_some()
+            || params.min_contrast.is_some()
+            || highlights;
+        let picked_early = !by_content
+            && store.is_some_and(|store| frames.frames.iter().any(|f| store.holds(&f.path)));
+        let frames = if picked_early {
+            frames.by_time(params, folder_config)
+        } else {
+            frames
+        };
+        if let Some(store) = store {
+            store.fetch(&frames.frames);
+        }
+
+        let (frames, skipped) = frames.validated(analysis, config.min_frame_age());
+        let frames = frames.within_brightness(params, analysis);
+        let frames = if highlights {
+            let padding = params
+                .highlight_padding
+                .map_or(0, |HumanDuration(padding)| padding.num_seconds());
+            frames.highlights(params, analysis, padding)
+        } else {
+            frames
+        };
+        let frames = if picked_early {
+            frames
+        } else {
+            frames.by_time(params, folder_config)
+        };
+
+        Ok((frames, skipped))
+    }
+
+    /// The stages that only apply to rendered output: dropping duplicates and
+    /// ramping the speed.
+    fn for_video(
+        self,
+        params: &QueryParams,
+        folder_config: &FolderConfig,
+        analysis: &Mutex<AnalysisCache>,
+    ) -> poem::Result<Self> {
+        let frames = if params.dedup.unwrap_or(false) {
+            self.without_duplicates(params.dedup_threshold.unwrap_or(3), analysis)
+        } else {
+            self
+        };
+        frames.ramped(params, &folder_config.timezone(), analysis)
+    }
+
+    /// The `time_of_day` and sampling selections, which only look at
+    /// timestamps.
+    fn by_time(self, params: &QueryParams, folder_config: &FolderConfig) -> Self {
+        let frames = match params.time_of_day {
+            Some(TimeOfDay(target)) => self.at_time_of_day(
+                &folder_config.timezone(),
+                target,
+                params.time_window.map(|d| d.0),
+            ),
+            None => self,
+        };
+        frames.sample(params)
+    }
+
+    /// Refuses a crop that doesn't fit inside the frames, judged by the first.
+    fn check_geometry(
+        &self,
+        geometry: &Geometry,
+        analysis: &Mutex<AnalysisCache>,
+    ) -> poem::Result<()> {
+        let Some(frame) = self.frames.first().filter(|_| geometry.crop.is_some()) else {
+            return Ok(());
+        };
+        let Some((width, height)) = analysis::frame_dimensions(analysis, &frame.path) else {
+            return Ok(());
+        };
+        geometry
+            .check_fits(width, height)
+            .map_err(|e| poem::Error::from_string(e, StatusCode::BAD_REQUEST))
+    }
+
     fn into_response(
         self,
-        fps: usize,
-        args_override: Option<Vec<String>>,
-        format: Option<&str>,
+        params: &QueryParams,
+        folder: &str,
+        index: &FrameIndex,
+        analysis: &Mutex<AnalysisCache>,
         cache: &mut VideoCache,
         headers: &HeaderMap,
     ) -> poem::Result<poem::Response> {
-        match format {
-            Some("zip") => self.into_zip(),
-            _ => self.into_mp4(fps, args_override, cache, headers),
+        let config = index.config();
+        let folder_config = config.folder(folder);
+        let (frames, skipped) = self.select(
+            params,
+            &folder_config,
+            config,
+            analysis,
+            index.store(),
+            params.highlights.unwrap_or(false),
+        )?;
+        let frames = frames.for_video(params, &folder_config, analysis)?;
+        if params.format.as_deref() != Some("zip") {
+            frames.check_geometry(&params.geometry(&folder_config)?, analysis)?;
         }
+
+        let mut response = match params.format.as_deref() {
+            Some("zip") => frames.into_zip(),
+            Some("stack") => frames.into_stack(
+                params.mode.unwrap_or_default(),
+                &params.geometry(&folder_config)?,
+            ),
+            Some("timeslice") => frames.into_timeslice(
+                params.orientation.unwrap_or_default(),
+                params.blend.unwrap_or(false),
+                &params.geometry(&folder_config)?,
+            ),
+            _ => frames.into_mp4(
+                params.fps.unwrap_or(20),
+                params.ffmpeg_args.as_ref().map(|x| x.clone().into()),
+                video_filters(params, &folder_config)?,
+                params
+                    .stabilize
+                    .unwrap_or(false)
+                    .then(|| params.stabilize_smoothing.unwrap_or(10)),
+                cache,
+                headers,
+            ),
+        }?;
+        response
+            .headers_mut()
+            .insert("X-Skipped-Frames", HeaderValue::from(skipped));
+        Ok(response)
     }
 }
 
+fn into_jpeg_response(image: image::RgbImage, frame_count: usize) -> poem::Result<poem::Response> {
+    let mut jpeg_data = Vec::new();
+    let encoder = image::codecs::jpeg::JpegEncoder::new_with_quality(&mut jpeg_data, 90);
+    if let Err(e) = image.write_with_encoder(encoder) {
+        eprintln!("Failed to encode image: {}", e);
+        return Ok(poem::Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body("failed to encode image"));
+    }
+
+    println!(
+        "Successfully created {:.1}MB image from {} frames",
+        jpeg_data.len() as f64 / 1_048_576.0,
+        frame_count
+    );
+
+    Ok(poem::Response::builder()
+        .header("Content-Type", "image/jpeg")
+        .body(jpeg_data))
+}
+
 #[derive(Clone)]
 struct FrameFolder(String);
 
@@ -419,18 +1411,17 @@ impl Display for FrameFolder {
 #[handler]
 fn week_handler(
     Path(folder): Path<String>,
-    Data(FrameFolder(frame_folder)): Data<&FrameFolder>,
+    Data(index): Data<&Arc<FrameIndex>>,
     params: Query<QueryParams>,
+    Data(analysis): Data<&Arc<Mutex<AnalysisCache>>>,
     Data(cache): Data<&Arc<Mutex<VideoCache>>>,
     headers: &HeaderMap,
 ) -> poem::Result<poem::Response> {
-    let resolved_folder = PathBuf::from(frame_folder).join(folder);
-    let frame_collection = FrameCollection::new(resolved_folder);
-
-    frame_collection.get_past_days(7).into_response(
-        params.fps.unwrap_or(20),
-        params.ffmpeg_args.as_ref().map(|x| x.clone().into()),
-        params.format.as_deref(),
+    index.get_past_days(&folder, 7).into_response(
+        &params,
+        &folder,
+        index,
+        analysis,
         &mut cache.lock().unwrap(),
         headers,
     )
@@ -439,18 +1430,17 @@ fn week_handler(
 #[handler]
 fn forty_eight_handler(
     Path(folder): Path<String>,
-    Data(FrameFolder(frame_folder)): Data<&FrameFolder>,
+    Data(index): Data<&Arc<FrameIndex>>,
     params: Query<QueryParams>,
+    Data(analysis): Data<&Arc<Mutex<AnalysisCache>>>,
     Data(cache): Data<&Arc<Mutex<VideoCache>>>,
     headers: &HeaderMap,
 ) -> poem::Result<poem::Response> {
-    let resolved_folder = PathBuf::from(frame_folder).join(folder);
-    let frame_collection = FrameCollection::new(resolved_folder);
-
-    frame_collection.get_past_days(2).into_response(
-        params.fps.unwrap_or(20),
-        params.ffmpeg_args.as_ref().map(|x| x.clone().into()),
-        params.format.as_deref(),
+    index.get_past_days(&folder, 2).into_response(
+        &params,
+        &folder,
+        index,
+        analysis,
         &mut cache.lock().unwrap(),
         headers,
     )
@@ -459,18 +1449,17 @@ fn forty_eight_handler(
 #[handler]
 fn twenty_four_handler(
     Path(folder): Path<String>,
-    Data(FrameFolder(frame_folder)): Data<&FrameFolder>,
+    Data(index): Data<&Arc<FrameIndex>>,
     params: Query<QueryParams>,
+    Data(analysis): Data<&Arc<Mutex<AnalysisCache>>>,
     Data(cache): Data<&Arc<Mutex<VideoCache>>>,
     headers: &HeaderMap,
 ) -> poem::Result<poem::Response> {
-    let resolved_folder = PathBuf::from(frame_folder).join(folder);
-    let frame_collection = FrameCollection::new(resolved_folder);
-
-    frame_collection.get_past_days(1).into_response(
-        params.fps.unwrap_or(20),
-        params.ffmpeg_args.as_ref().map(|x| x.clone().into()),
-        params.format.as_deref(),
+    index.get_past_days(&folder, 1).into_response(
+        &params,
+        &folder,
+        index,
+        analysis,
         &mut cache.lock().unwrap(),
         headers,
     )
@@ -479,72 +1468,99 @@ fn twenty_four_handler(
 #[handler]
 fn day_handler(
     Path((day, folder)): Path<(String, String)>,
-    Data(FrameFolder(frame_folder)): Data<&FrameFolder>,
+    Data(index): Data<&Arc<FrameIndex>>,
     params: Query<QueryParams>,
+    Data(analysis): Data<&Arc<Mutex<AnalysisCache>>>,
     Data(cache): Data<&Arc<Mutex<VideoCache>>>,
     headers: &HeaderMap,
 ) -> poem::Result<poem::Response> {
-    let resolved_folder = PathBuf::from(frame_folder).join(folder);
-    let frame_collection = FrameCollection::new(resolved_folder);
-
-    // Assume the day is in the format YYYY-MM-DD and the timezone is Eastern
-    // TODO: what do we do for DST?
-    let start = format!("{}T00:00:00-04:00", day);
-    let end = format!("{}T23:59:59-04:00", day);
-    let start = DateTime::parse_from_rfc3339(&start).unwrap();
-    let end = DateTime::parse_from_rfc3339(&end).unwrap();
-
-    frame_collection
-        .get_range(start.into(), end.into())
-        .into_response(
-            params.fps.unwrap_or(20),
-            params.ffmpeg_args.as_ref().map(|x| x.clone().into()),
-            params.format.as_deref(),
-            &mut cache.lock().unwrap(),
-            headers,
+    let day = NaiveDate::parse_from_str(&day, "%Y-%m-%d").map_err(|e| {
+        poem::Error::from_string(
+            format!("Invalid day {}: {}", day, e),
+            StatusCode::BAD_REQUEST,
         )
+    })?;
+    // Midnight to midnight in the folder's time zone, however long the day is
+    let tz = index.config().folder(&folder).timezone();
+    let midnight = |day: NaiveDate| {
+        tz.from_local_datetime(&day.and_time(NaiveTime::MIN))
+            .earliest()
+            .map(|time| time.to_utc())
+    };
+    let (Some(start), Some(end)) = (midnight(day), day.succ_opt().and_then(midnight)) else {
+        return Err(poem::Error::from_string(
+            format!("Invalid day {}", day),
+            StatusCode::BAD_REQUEST,
+        ));
+    };
+
+    index.get_range(&folder, start, end).into_response(
+        &params,
+        &folder,
+        index,
+        analysis,
+        &mut cache.lock().unwrap(),
+        headers,
+    )
 }
 
 #[handler]
 fn exact_handler(
     Path((start, end, folder)): Path<(String, String, String)>,
-    Data(FrameFolder(frame_folder)): Data<&FrameFolder>,
+    Data(index): Data<&Arc<FrameIndex>>,
     params: Query<QueryParams>,
+    Data(analysis): Data<&Arc<Mutex<AnalysisCache>>>,
     Data(cache): Data<&Arc<Mutex<VideoCache>>>,
     headers: &HeaderMap,
 ) -> poem::Result<poem::Response> {
-    let resolved_folder = PathBuf::from(frame_folder).join(folder);
-    let frame_collection = FrameCollection::new(resolved_folder);
-
     let start = DateTime::parse_from_rfc3339(&start).unwrap();
     let end = DateTime::parse_from_rfc3339(&end).unwrap();
 
-    frame_collection
-        .get_range(start.into(), end.into())
+    index
+        .get_range(&folder, start.into(), end.into())
         .into_response(
-            params.fps.unwrap_or(20),
-            params.ffmpeg_args.as_ref().map(|x| x.clone().into()),
-            params.format.as_deref(),
+            &params,
+            &folder,
+            index,
+            analysis,
             &mut cache.lock().unwrap(),
             headers,
         )
 }
 
 #[handler]
-fn timelapse_index_handler(Data(FrameFolder(frame_folder)): Data<&FrameFolder>) -> Markup {
-    // Read the files in the folder
-    let folders: Vec<String> = fs::read_dir(frame_folder)
-        .unwrap()
-        .filter_map(|entry| {
-            let entry = entry.unwrap();
-            let file_name = entry.file_name().into_string().unwrap();
-            if entry.file_type().unwrap().is_dir() {
-                Some(file_name)
-            } else {
-                None
-            }
+fn activity_handler(
+    Path((start, end, folder)): Path<(String, String, String)>,
+    Data(index): Data<&Arc<FrameIndex>>,
+    params: Query<QueryParams>,
+    Data(analysis): Data<&Arc<Mutex<AnalysisCache>>>,
+) -> poem::Result<Json<Vec<ActivityInterval>>> {
+    let parse = |time: &str| {
+        DateTime::parse_from_rfc3339(time).map_err(|e| {
+            poem::Error::from_string(
+                format!("Invalid time {}: {}", time, e),
+                StatusCode::BAD_REQUEST,
+            )
         })
-        .collect();
+    };
+    let (start, end) = (parse(&start)?, parse(&end)?);
+
+    let config = index.config();
+    let (frames, _) = index.get_range(&folder, start.into(), end.into()).select(
+        &params,
+        &config.folder(&folder),
+        config,
+        analysis,
+        index.store(),
+        false,
+    )?;
+
+    Ok(Json(frames.activity(&params, analysis)))
+}
+
+#[handler]
+fn timelapse_index_handler(Data(index): Data<&Arc<FrameIndex>>) -> Markup {
+    let folders = index.folders();
 
     html! {
         style {
@@ -583,6 +1599,46 @@ fn timelapse_index_handler(Data(FrameFolder(frame_folder)): Data<&FrameFolder>)
             li { pre { "GET /timelapse/1w/:folder" } }
             li { pre { "GET /timelapse/day/YYYY-MM-DD/:folder" } }
             li { pre { "GET /timelapse/from/[ISO8601]/to/[ISO8601]/:folder" } }
+            li { pre { "GET /timelapse/activity/from/[ISO8601]/to/[ISO8601]/:folder" } "JSON list of intervals with activity" }
+        }
+        h2 { "Query parameters" }
+        ul {
+            li { pre { "fps=20" } "Output frame rate" }
+            li { pre { "format=zip" } "Download the frames instead of a video" }
+            li { pre { "format=stack" } "Combine the frames into a single image" }
+            li { pre { "mode=max" } "With format=stack, keep the brightest (max), average (mean) or median value of each pixel" }
+            li { pre { "format=timeslice" } "One image with each column taken from a different frame" }
+            li { pre { "orientation=vertical" } "With format=timeslice, slice into columns (vertical) or rows (horizontal)" }
+            li { pre { "blend=true" } "With format=timeslice, crossfade between slices" }
+            li { pre { "every=N" } "Keep only every Nth frame" }
+            li { pre { "interval=5m" } "Keep at most one frame per interval (s, m, h or d)" }
+            li { pre { "limit=N" } "Keep at most N frames, evenly spaced" }
+            li { pre { "time_of_day=12:00" } "Keep the frame closest to this time on each day, in the folder's time zone" }
+            li { pre { "time_window=30m" } "With time_of_day, skip days without a frame this close to it" }
+            li { pre { "daylight=true" } "Drop night frames, using the folder's configured latitude and longitude" }
+            li { pre { "twilight=true" } "With daylight, also keep frames from civil twilight" }
+            li { pre { "min_brightness=20" } "Drop frames darker than this mean luminance (0-255)" }
+            li { pre { "max_brightness=235" } "Drop frames brighter than this mean luminance (0-255)" }
+            li { pre { "min_contrast=5" } "Drop flat frames whose luminance deviation is below this" }
+            li { pre { "deflicker=5" } "Smooth out auto-exposure flicker over this many frames (2-129)" }
+            li { pre { "stabilize=true" } "Remove camera shake" }
+            li { pre { "stabilize_smoothing=10" } "With stabilize, smooth camera motion over this many frames either side" }
+            li { pre { "crop=x,y,w,h" } "Crop each frame, in original frame pixels" }
+            li { pre { "rotate=90" } "Rotate each frame clockwise by 90, 180 or 270 degrees" }
+            li { pre { "scale=1280x720" } "Scale each frame to this size" }
+            li { pre { "width=1280" } "Scale each frame to this width, keeping the aspect ratio" }
+            li { pre { "interpolate=mci" } "Synthesize in-between frames with motion interpolation (mci) or crossfades (blend)" }
+            li { pre { "output_fps=60" } "With interpolate, the frame rate to interpolate up to" }
+            li { pre { "dedup=true" } "Drop frames that look the same as the previous one" }
+            li { pre { "dedup_threshold=3" } "With dedup, frames differing by at most this many of 64 perceptual hash bits are duplicates" }
+            li { pre { "roi=x,y,w,h;..." } "Only count activity inside these regions, as fractions of the frame" }
+            li { pre { "activity_threshold=0.05" } "Fraction of the frame that must change to count as activity" }
+            li { pre { "activity_gap=5m" } "Merge activity closer together than this" }
+            li { pre { "highlights=true" } "Only keep frames from periods of activity" }
+            li { pre { "highlight_padding=2m" } "With highlights, keep this much before and after each period" }
+            li { pre { "slow=14:00-15:00,..." } "Slow down these times of day, in the folder's time zone" }
+            li { pre { "ramp=activity" } "Slow down frames where the scene is changing" }
+            li { pre { "slow_factor=4" } "How many times slower slowed down frames play" }
         }
     }
 }
@@ -595,16 +1651,253 @@ fn index_redirect_handler() -> impl IntoResponse {
         .body(())
 }
 
+/// Largest frame cameras may upload.
+const MAX_INGEST_BYTES: usize = 50 * 1024 * 1024;
+
+#[derive(Deserialize)]
+struct IngestParams {
+    timestamp: Option<String>,
+}
+
+/// When an uploaded frame was taken, from the `timestamp` query parameter or
+/// `X-Frame-Timestamp` header as unix seconds or RFC 3339. Defaults to now, to
+/// the second.
+fn ingest_time(params: &IngestParams, headers: &HeaderMap) -> poem::Result<DateTime<Utc>> {
+    let value = match &params.timestamp {
+        Some(value) => value.as_str(),
+        None => match headers.get("x-frame-timestamp") {
+            Some(value) => value.to_str().unwrap_or_default(),
+            None => return Ok(Utc::now().trunc_subsecs(0)),
+        },
+    };
+    let time = match DateTime::parse_from_rfc3339(value) {
+        Ok(time) => Some(time.to_utc()),
+        Err(_) => TimestampUnit::S
+            .parse(value)
+            .and_then(|(seconds, nanos)| DateTime::from_timestamp(seconds, nanos)),
+    };
+    time.filter(|time| time.timestamp() >= 0).ok_or_else(|| {
+        poem::Error::from_string(
+            format!("Invalid frame timestamp: {}", value),
+            StatusCode::BAD_REQUEST,
+        )
+    })
+}
+
+/// Compares bearer tokens without leaking how much of them matched.
+fn token_matches(headers: &HeaderMap, token: &str) -> bool {
+    let Some(given) = headers
+        .get(http::header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+    else {
+        return false;
+    };
+    given.len() == token.len()
+        && given
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// Turns away uploads without the ingest token before their body is read.
+async fn require_ingest_token(req: poem::Request) -> poem::Result<poem::Request> {
+    let token = req
+        .data::<Arc<Config>>()
+        .and_then(|config| config.ingest_token());
+    let Some(token) = token else {
+        return Err(poem::Error::from_string(
+            "Frame uploads are not enabled",
+            StatusCode::FORBIDDEN,
+        ));
+    };
+    if !token_matches(req.headers(), &token) {
+        return Err(poem::Error::from_response(
+            poem::Response::builder()
+                .status(StatusCode::UNAUTHORIZED)
+                .header(http::header::WWW_AUTHENTICATE, "Bearer")
+                .finish(),
+        ));
+    }
+    Ok(req)
+}
+
+/// Accepts a JPEG frame pushed by a camera, for cameras without write access
+/// to the frame folders.
+#[handler]
+fn ingest_handler(
+    Path(folder): Path<String>,
+    Data(index): Data<&Arc<FrameIndex>>,
+    Query(params): Query<IngestParams>,
+    headers: &HeaderMap,
+    body: Vec<u8>,
+) -> poem::Result<poem::Response> {
+    let taken = ingest_time(&params, headers)?;
+    if !analysis::is_complete_jpeg(&body) {
+        return Err(poem::Error::from_string(
+            "Frames must be complete JPEG images",
+            StatusCode::BAD_REQUEST,
+        ));
+    }
+
+    match index.ingest(&folder, taken, &body) {
+        Ok(path) => {
+            println!("Ingested {}", path.display());
+            Ok(poem::Response::builder().status(StatusCode::CREATED).body(
+                path.file_name()
+                    .unwrap_or_default()
+                    .to_string_lossy()
+                    .to_string(),
+            ))
+        }
+        Err(e) if e.kind() == io::ErrorKind::InvalidInput => Err(poem::Error::from_string(
+            e.to_string(),
+            StatusCode::BAD_REQUEST,
+        )),
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Err(poem::Error::from_string(
+            "A frame with this timestamp already exists",
+            StatusCode::CONFLICT,
+        )),
+        Err(e) => {
+            eprintln!("Failed to ingest frame into {}: {}", folder, e);
+            Ok(poem::Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body("Failed to store frame"))
+        }
+    }
+}
+
+/// Capture health of each configured camera.
+#[handler]
+fn capture_status_handler(
+    Data(capture): Data<&Arc<Capture>>,
+) -> Json<BTreeMap<String, CameraStatus>> {
+    Json(capture.status())
+}
+
 #[handler]
 fn healthcheck() -> impl IntoResponse {
     poem::Response::builder().status(StatusCode::OK).body("OK")
 }
 
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let host = "0.0.0.0";
+    let port: i32 = env::var("PORT").map(|x| x.parse().unwrap()).unwrap_or(8102);
+    let frame_folder =
+        FrameFolder(env::var("OUTPUT_FOLDER").expect("OUTPUT_FOLDER env var required"));
+    let cache = Arc::new(Mutex::new(VideoCache::new(10)));
+    let config = Arc::new(Config::load());
+    let catalog =
+        config.catalog.as_ref().map(|path| {
+            Arc::new(Catalog::open(path).unwrap_or_else(|e| {
+                panic!("Failed to open frame catalog {}: {}", path.display(), e)
+            }))
+        });
+    let analysis = Arc::new(Mutex::new(AnalysisCache::with_catalog(catalog.clone())));
+    println!(
+        "OUTPUT_FOLDER: {}\nPort: {}\nHost: {}",
+        frame_folder, port, host
+    );
+    // Several roots can be given separated by `:`, most preferred first
+    let roots: Vec<PathBuf> = env::split_paths(&frame_folder.0).collect();
+    let store = config.object_store.as_ref().map(|store| {
+        ObjectStore::new(store).unwrap_or_else(|e| panic!("Failed to set up object storage: {}", e))
+    });
+    let index = FrameIndex::new(roots, config.clone(), catalog, store);
+    if let Err(e) = index.watch() {
+        eprintln!("Failed to watch {} for new frames: {}", frame_folder, e);
+    }
+    index.spawn_rescan(config.index_rescan_interval());
+    let capture = Capture::start(&config.cameras, index.clone());
+    println!("http://{}:{}/timelapse/24/:folder", host, port);
+    println!("http://{}:{}/timelapse/48/:folder", host, port);
+    println!("http://{}:{}/timelapse/1w/:folder", host, port);
+    println!("http://{}:{}/timelapse/day/YYYY-MM-DD/:folder", host, port);
+    println!(
+        "http://{}:{}/timelapse/from/[ISO8601]/to/[ISO8601]/:folder",
+        host, port
+    );
+    println!(
+        "http://{}:{}/timelapse/activity/from/[ISO8601]/to/[ISO8601]/:folder",
+        host, port
+    );
+    println!("POST http://{}:{}/timelapse/ingest/:folder", host, port);
+    println!("http://{}:{}/timelapse/capture/status", host, port);
+    let twenty_four_service = Route::new().at("/:folder", get(twenty_four_handler));
+    let forty_eight_service = Route::new().at("/:folder", get(forty_eight_handler));
+    let week_service = Route::new().at("/:folder", get(week_handler));
+    let day_service = Route::new().at("/:day/:folder", get(day_handler));
+    let exact_service = Route::new().at("/:start/to/:end/:folder", get(exact_handler));
+    let activity_service = Route::new().at("/from/:start/to/:end/:folder", get(activity_handler));
+    let ingest_service = Route::new()
+        .at("/:folder", post(ingest_handler))
+        .with(SizeLimit::new(MAX_INGEST_BYTES))
+        .before(require_ingest_token);
+
+    let route = Route::new()
+        .nest("/timelapse/24", twenty_four_service)
+        .nest("/timelapse/48", forty_eight_service)
+        .nest("/timelapse/1w", week_service)
+        .nest("/timelapse/day", day_service)
+        .nest("/timelapse/from", exact_service)
+        .nest("/timelapse/activity", activity_service)
+        .nest("/timelapse/ingest", ingest_service)
+        .at("/timelapse/capture/status", get(capture_status_handler))
+        .at("/timelapse/", get(timelapse_index_handler))
+        .at("/timelapse", get(timelapse_index_handler))
+        .at("/healthcheck", get(healthcheck))
+        .at("/", get(index_redirect_handler))
+        .data(index)
+        .data(capture)
+        .data(config)
+        .data(analysis)
+        .data(cache);
+    Server::new(TcpListener::bind(format!("{host}:{port}")))
+        .run(route)
+        .await?;
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use poem::http::HeaderValue;
 
+    #[test]
+    fn test_ingest_time_and_token() {
+        let params = |timestamp: Option<&str>| IngestParams {
+            timestamp: timestamp.map(str::to_string),
+        };
+        let mut headers = HeaderMap::new();
+        assert_eq!(
+            ingest_time(&params(Some("1700000000.5")), &headers).unwrap(),
+            DateTime::from_timestamp(1700000000, 500_000_000).unwrap()
+        );
+        assert!(ingest_time(&params(Some("yesterday")), &headers).is_err());
+        assert!(ingest_time(&params(Some("-5")), &headers).is_err());
+        let now = ingest_time(&params(None), &headers).unwrap();
+        assert_eq!(now.timestamp_subsec_nanos(), 0);
+
+        headers.insert(
+            "x-frame-timestamp",
+            HeaderValue::from_static("2023-11-14T22:13:20Z"),
+        );
+        assert_eq!(
+            ingest_time(&params(None), &headers).unwrap().timestamp(),
+            1700000000
+        );
+
+        assert!(!token_matches(&headers, "secret"));
+        headers.insert(
+            http::header::AUTHORIZATION,
+            HeaderValue::from_static("Bearer secret"),
+        );
+        assert!(token_matches(&headers, "secret"));
+        assert!(!token_matches(&headers, "secret2"));
+    }
+
     #[test]
     fn test_handle_range_requests_sets_cache_headers() {
         let test_data = vec![1, 2, 3, 4, 5];
@@ -653,47 +1946,242 @@ mod tests {
         // Verify the response is partial content
         assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
     }
-}
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let host = "0.0.0.0";
-    let port: i32 = env::var("PORT").map(|x| x.parse().unwrap()).unwrap_or(8102);
-    let frame_folder =
-        FrameFolder(env::var("OUTPUT_FOLDER").expect("OUTPUT_FOLDER env var required"));
-    let cache = Arc::new(Mutex::new(VideoCache::new(10)));
-    println!(
-        "OUTPUT_FOLDER: {}\nPort: {}\nHost: {}",
-        frame_folder, port, host
-    );
-    println!("http://{}:{}/timelapse/24/:folder", host, port);
-    println!("http://{}:{}/timelapse/48/:folder", host, port);
-    println!("http://{}:{}/timelapse/1w/:folder", host, port);
-    println!("http://{}:{}/timelapse/day/YYYY-MM-DD/:folder", host, port);
-    println!(
-        "http://{}:{}/timelapse/from/[ISO8601]/to/[ISO8601]/:folder",
-        host, port
-    );
-    let twenty_four_service = Route::new().at("/:folder", get(twenty_four_handler));
-    let forty_eight_service = Route::new().at("/:folder", get(forty_eight_handler));
-    let week_service = Route::new().at("/:folder", get(week_handler));
-    let day_service = Route::new().at("/:day/:folder", get(day_handler));
-    let exact_service = Route::new().at("/:start/to/:end/:folder", get(exact_handler));
+    fn frames_at(timestamps: &[i64]) -> FrameCollection {
+        FrameCollection {
+            frames: timestamps
+                .iter()
+                .map(|&timestamp| Frame {
+                    path: PathBuf::from(format!("{}.jpg", timestamp)),
+                    timestamp,
+                    subsec_nanos: 0,
+                    hold: 1.0,
+                })
+                .collect(),
+        }
+    }
 
-    let route = Route::new()
-        .nest("/timelapse/24", twenty_four_service)
-        .nest("/timelapse/48", forty_eight_service)
-        .nest("/timelapse/1w", week_service)
-        .nest("/timelapse/day", day_service)
-        .nest("/timelapse/from", exact_service)
-        .at("/timelapse/", get(timelapse_index_handler))
-        .at("/timelapse", get(timelapse_index_handler))
-        .at("/healthcheck", get(healthcheck))
-        .at("/", get(index_redirect_handler))
-        .data(frame_folder)
-        .data(cache);
-    Server::new(TcpListener::bind(format!("{host}:{port}")))
-        .run(route)
-        .await?;
-    Ok(())
+    fn timestamps(collection: &FrameCollection) -> Vec<i64> {
+        collection.frames.iter().map(|f| f.timestamp).collect()
+    }
+
+    #[test]
+    fn test_video_filters() {
+        let folder_config = FolderConfig::default();
+        assert!(video_filters(&QueryParams::default(), &folder_config)
+            .unwrap()
+            .is_empty());
+
+        let params = QueryParams {
+            deflicker: Some(7),
+            ..Default::default()
+        };
+        assert_eq!(
+            video_filters(&params, &folder_config).unwrap(),
+            vec!["deflicker=size=7"]
+        );
+
+        let params = QueryParams {
+            deflicker: Some(7),
+            width: Some(640),
+            ..Default::default()
+        };
+        let folder_config = FolderConfig {
+            rotate: Some(90),
+            ..Default::default()
+        };
+        assert_eq!(
+            video_filters(&params, &folder_config).unwrap(),
+            vec!["transpose=clock", "scale=640:-2", "deflicker=size=7"]
+        );
+
+        let params = QueryParams {
+            deflicker: Some(500),
+            ..Default::default()
+        };
+        assert!(video_filters(&params, &folder_config).is_err());
+
+        let params = QueryParams {
+            interpolate: Some(Interpolation::Blend),
+            output_fps: Some(30),
+            ..Default::default()
+        };
+        assert_eq!(
+            video_filters(&params, &folder_config).unwrap(),
+            vec!["transpose=clock", "framerate=fps=30"]
+        );
+    }
+
+    #[test]
+    fn test_transforms_cache_evicts_oldest() {
+        let mut cache = VideoCache::new(2);
+        for digest in 0..3 {
+            cache.set_transforms(digest, NamedTempFile::new().unwrap());
+        }
+        assert!(cache.get_transforms(0).is_none());
+        assert!(cache.get_transforms(1).is_some());
+        assert!(cache.get_transforms(2).is_some());
+    }
+
+    #[test]
+    fn test_highlights_keep_padded_activity() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut taken = Vec::new();
+        for i in 0..10i64 {
+            let timestamp = i * 60;
+            // The scene changes between the fifth and sixth frames only
+            let level = if i < 5 { 40 } else { 200 };
+            image::GrayImage::from_pixel(32, 24, image::Luma([level]))
+                .save(dir.path().join(format!("{}.jpg", timestamp)))
+                .unwrap();
+            taken.push(timestamp);
+        }
+        let frames = FrameCollection {
+            frames: taken
+                .iter()
+                .map(|&timestamp| Frame {
+                    path: dir.path().join(format!("{}.jpg", timestamp)),
+                    timestamp,
+                    subsec_nanos: 0,
+                    hold: 1.0,
+                })
+                .collect(),
+        };
+
+        let analysis = Mutex::new(AnalysisCache::new());
+        let kept = frames.highlights(&QueryParams::default(), &analysis, 60);
+        assert_eq!(timestamps(&kept), vec![240, 300, 360]);
+    }
+
+    #[test]
+    fn test_slow_time_ranges_hold_frames_longer() {
+        let params = QueryParams {
+            slow: TimeRanges::parse("00:01-00:03,23:59-00:00"),
+            slow_factor: Some(3.0),
+            ..Default::default()
+        };
+        let analysis = Mutex::new(AnalysisCache::new());
+        let ramped = frames_at(&[0, 60, 120, 180, 86_340])
+            .ramped(&params, &Utc, &analysis)
+            .unwrap();
+        let holds: Vec<f32> = ramped.frames.iter().map(|f| f.hold).collect();
+        assert_eq!(holds, vec![1.0, 3.0, 3.0, 1.0, 3.0]);
+        assert!(ramped.concat_list(20).contains("outpoint 0.1500\n"));
+    }
+
+    #[test]
+    fn test_human_duration_parse() {
+        assert_eq!(HumanDuration::parse("90").unwrap().0.num_seconds(), 90);
+        assert_eq!(HumanDuration::parse("5m").unwrap().0.num_seconds(), 300);
+        assert_eq!(HumanDuration::parse("2h").unwrap().0.num_seconds(), 7200);
+        assert!(HumanDuration::parse("5x").is_none());
+    }
+
+    #[test]
+    fn test_sample_every_and_limit() {
+        let params = QueryParams {
+            every: NonZeroUsize::new(2),
+            ..Default::default()
+        };
+        let sampled = frames_at(&[0, 1, 2, 3, 4, 5, 6]).sample(&params);
+        assert_eq!(timestamps(&sampled), vec![0, 2, 4, 6]);
+
+        let params = QueryParams {
+            limit: NonZeroUsize::new(3),
+            ..Default::default()
+        };
+        let sampled = frames_at(&[0, 1, 2, 3, 4, 5, 6, 7, 8]).sample(&params);
+        assert_eq!(timestamps(&sampled), vec![0, 4, 8]);
+    }
+
+    #[test]
+    fn test_zero_every_and_limit_are_rejected() {
+        for query in ["every=0", "limit=0"] {
+            let request = poem::Request::builder()
+                .uri(format!("/?{}", query).parse().unwrap())
+                .finish();
+            assert!(request.params::<QueryParams>().is_err(), "{}", query);
+        }
+        let request = poem::Request::builder()
+            .uri("/?every=2&limit=1".parse().unwrap())
+            .finish();
+        assert!(request.params::<QueryParams>().is_ok());
+    }
+
+    #[test]
+    fn test_sample_interval_picks_closest_to_bucket_start() {
+        let params = QueryParams {
+            interval: HumanDuration::parse("1m"),
+            ..Default::default()
+        };
+        let sampled = frames_at(&[0, 20, 50, 65, 61, 130, 190]).sample(&params);
+        assert_eq!(timestamps(&sampled), vec![0, 61, 130, 190]);
+    }
+
+    #[test]
+    fn test_at_time_of_day_picks_closest_frame_per_day() {
+        let noon = NaiveTime::from_hms_opt(12, 0, 0).unwrap();
+        let day = 86_400;
+        let noon_offset = 43_200;
+        let frames = frames_at(&[
+            noon_offset - 600,
+            noon_offset + 60,
+            noon_offset + 3_600,
+            day + noon_offset - 7_200,
+            2 * day + 100,
+        ]);
+
+        let picked = frames.at_time_of_day(&Utc, noon, None);
+        assert_eq!(
+            timestamps(&picked),
+            vec![noon_offset + 60, day + noon_offset - 7_200, 2 * day + 100]
+        );
+
+        let frames = frames_at(&[noon_offset + 60, day + noon_offset - 7_200]);
+        let picked = frames.at_time_of_day(&Utc, noon, Some(chrono::Duration::hours(1)));
+        assert_eq!(timestamps(&picked), vec![noon_offset + 60]);
+    }
+
+    #[test]
+    fn test_mixed_formats_are_converted_to_jpeg() {
+        let dir = tempfile::tempdir().unwrap();
+        let paths: Vec<PathBuf> = ["1.jpg", "2.png", "3.webp"]
+            .iter()
+            .map(|name| dir.path().join(name))
+            .collect();
+        for path in &paths {
+            image::RgbImage::from_pixel(8, 8, image::Rgb([10, 20, 30]))
+                .save(path)
+                .unwrap();
+        }
+        let collection = |paths: &[PathBuf]| FrameCollection {
+            frames: paths
+                .iter()
+                .enumerate()
+                .map(|(i, path)| Frame {
+                    path: path.clone(),
+                    timestamp: i as i64,
+                    subsec_nanos: 0,
+                    hold: 1.0,
+                })
+                .collect(),
+        };
+
+        let converted = tempfile::tempdir().unwrap();
+        let frames = collection(&paths)
+            .with_uniform_format(converted.path())
+            .unwrap();
+        assert_eq!(frames.frames[0].path, paths[0]);
+        for frame in &frames.frames[1..] {
+            assert!(frame.path.starts_with(converted.path()));
+            assert_eq!(FrameFormat::of(&frame.path), Some(FrameFormat::Jpeg));
+            assert_eq!(image::open(&frame.path).unwrap().width(), 8);
+        }
+
+        // Frames all in one format are used as they are
+        let frames = collection(&paths[1..2])
+            .with_uniform_format(converted.path())
+            .unwrap();
+        assert_eq!(frames.frames[0].path, paths[1]);
+    }
 }
@@ -0,0 +1,503 @@
+use chrono::format::{Item, StrftimeItems};
+use chrono::{DateTime, NaiveDate, NaiveDateTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::UNIX_EPOCH;
+
+/// How frames are timestamped. By default frames are named
+/// `<unix seconds>.jpg`, or any other extension in [`FrameFormat`].
+///
+/// ```toml
+/// [folders.archive]
+/// filename_prefix = "cam1_"
+/// timestamp_format = "%Y%m%d_%H%M%S%.f"
+///
+/// [folders.sequential]
+/// timestamp_source = "exif"
+/// ```
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct FrameNaming {
+    /// Text before the timestamp, such as `cam1_`
+    #[serde(default)]
+    pub filename_prefix: String,
+    /// Unit of epoch timestamps: `s`, `ms`, `us` or `ns`
+    #[serde(default)]
+    pub timestamp_unit: TimestampUnit,
+    /// strftime-style format for names that aren't epoch timestamps. Times
+    /// without an offset are in the folder's time zone.
+    pub timestamp_format: Option<TimestampFormat>,
+    /// Where timestamps come from, for cameras that don't put the time in
+    /// the file name
+    #[serde(default)]
+    pub timestamp_source: TimestampSource,
+    /// IANA time zone of the camera, such as `America/New_York`. Defaults to
+    /// the top-level `timezone`.
+    pub timezone: Option<Tz>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TimestampSource {
+    #[default]
+    Filename,
+    /// EXIF DateTimeOriginal, falling back to the modification time for
+    /// frames without it
+    Exif,
+    /// The file's modification time
+    Mtime,
+}
+
+/// Image formats frames can be stored in, recognized by their extension.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FrameFormat {
+    Jpeg,
+    Png,
+    Webp,
+}
+
+impl FrameFormat {
+    pub fn from_extension(extension: &str) -> Option<Self> {
+        match extension.to_ascii_lowercase().as_str() {
+            "jpg" | "jpeg" => Some(FrameFormat::Jpeg),
+            "png" => Some(FrameFormat::Png),
+            "webp" => Some(FrameFormat::Webp),
+            _ => None,
+        }
+    }
+
+    pub fn of(path: &Path) -> Option<Self> {
+        Self::from_extension(path.extension()?.to_str()?)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TimestampUnit {
+    #[default]
+    S,
+    Ms,
+    Us,
+    Ns,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TimestampFormat(String);
+
+impl FromStr for TimestampFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() || StrftimeItems::new(s).any(|item| item == Item::Error) {
+            return Err(format!("invalid timestamp format: {}", s));
+        }
+        Ok(TimestampFormat(s.to_string()))
+    }
+}
+
+impl<'de> Deserialize<'de> for TimestampFormat {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let s: String = Deserialize::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+impl FrameNaming {
+    /// The time zone that times without an offset are in.
+    pub fn timezone(&self) -> Tz {
+        self.timezone.unwrap_or(Tz::UTC)
+    }
+
+    /// The timestamp of the frame at `path`, as unix seconds and the
+    /// sub-second part in nanoseconds. `None` if it isn't a frame.
+    pub fn frame_timestamp(&self, path: &Path, cache: &TimestampCache) -> Option<(i64, u32)> {
+        match self.timestamp_source {
+            TimestampSource::Filename => self.timestamp(path.file_name()?.to_str()?),
+            source => {
+                FrameFormat::of(path)?;
+                cache.get_or_read(path, source, self.timezone())
+            }
+        }
+    }
+
+    /// The longest file name prefix shared by every frame from `start` to
+    /// `end` seconds, for listing object storage by key. `None` unless names
+    /// are epoch timestamps, the only names that sort by time.
+    pub fn range_prefix(&self, start: i64, end: i64) -> Option<String> {
+        if self.timestamp_format.is_some()
+            || self.timestamp_source != TimestampSource::Filename
+            || start < 0
+        {
+            return None;
+        }
+        let per_second = 1_000_000_000 / self.timestamp_unit.nanos();
+        let start = (start as i128 * per_second).to_string();
+        let end = (end as i128 * per_second).to_string();
+        if start.len() != end.len() {
+            return None;
+        }
+        let common: String = start
+            .chars()
+            .zip(end.chars())
+            .take_while(|(a, b)| a == b)
+            .map(|(a, _)| a)
+            .collect();
+        Some(format!("{}{}", self.filename_prefix, common))
+    }
+
+    /// The file name a frame taken at `seconds` and `nanos` past the epoch
+    /// gets, for times after 1970. Anything finer than the timestamp unit is
+    /// dropped, so names stay whole numbers of it.
+    pub fn file_name(&self, seconds: i64, nanos: u32, extension: &str) -> Option<String> {
+        let stem = match &self.timestamp_format {
+            Some(TimestampFormat(format)) => {
+                let time =
+                    DateTime::from_timestamp(seconds, nanos)?.with_timezone(&self.timezone());
+                time.format(format).to_string()
+            }
+            None => {
+                let unit = self.timestamp_unit.nanos();
+                let total = i128::from(seconds) * 1_000_000_000 + i128::from(nanos);
+                total.div_euclid(unit).to_string()
+            }
+        };
+        Some(format!("{}{}.{}", self.filename_prefix, stem, extension))
+    }
+
+    /// The timestamp in a frame's file name.
+    pub fn timestamp(&self, file_name: &str) -> Option<(i64, u32)> {
+        let (stem, extension) = file_name.rsplit_once('.')?;
+        FrameFormat::from_extension(extension)?;
+        let stem = stem.strip_prefix(self.filename_prefix.as_str())?;
+        match &self.timestamp_format {
+            Some(TimestampFormat(format)) => parse_formatted(stem, format, self.timezone()),
+            None => self.timestamp_unit.parse(stem),
+        }
+    }
+}
+
+impl TimestampUnit {
+    fn nanos(self) -> i128 {
+        match self {
+            TimestampUnit::S => 1_000_000_000,
+            TimestampUnit::Ms => 1_000_000,
+            TimestampUnit::Us => 1_000,
+            TimestampUnit::Ns => 1,
+        }
+    }
+
+    /// Parses an epoch timestamp in this unit, with an optional decimal
+    /// fraction such as `1700000000.250`.
+    pub fn parse(self, stem: &str) -> Option<(i64, u32)> {
+        let (whole, fraction) = stem.split_once('.').unwrap_or((stem, ""));
+        let mut nanos = whole.parse::<i64>().ok()? as i128 * self.nanos();
+
+        if !fraction.is_empty() {
+            if !fraction.bytes().all(|b| b.is_ascii_digit()) {
+                return None;
+            }
+            let digits = &fraction[..fraction.len().min(9)];
+            let scale = 10i128.pow(digits.len() as u32);
+            let fraction = digits.parse::<i128>().ok()? * self.nanos() / scale;
+            nanos += if whole.starts_with('-') {
+                -fraction
+            } else {
+                fraction
+            };
+        }
+
+        let seconds = i64::try_from(nanos.div_euclid(1_000_000_000)).ok()?;
+        Some((seconds, nanos.rem_euclid(1_000_000_000) as u32))
+    }
+}
+
+/// Timestamps read from frame files rather than their names, so EXIF isn't
+/// parsed again on every rescan. Entries are reused for as long as the
+/// file's size and modification time are unchanged.
+#[derive(Default)]
+pub struct TimestampCache {
+    entries: Mutex<HashMap<PathBuf, CachedTimestamp>>,
+}
+
+struct CachedTimestamp {
+    size: u64,
+    mtime: i64,
+    timestamp: (i64, u32),
+}
+
+impl TimestampCache {
+    /// Remembers a timestamp worked out earlier, such as one loaded from the
+    /// catalog.
+    pub fn insert(&self, path: PathBuf, size: u64, mtime: i64, timestamp: (i64, u32)) {
+        self.entries.lock().unwrap().insert(
+            path,
+            CachedTimestamp {
+                size,
+                mtime,
+                timestamp,
+            },
+        );
+    }
+
+    fn get_or_read(&self, path: &Path, source: TimestampSource, tz: Tz) -> Option<(i64, u32)> {
+        let metadata = fs::metadata(path).ok()?;
+        let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+        let (size, mtime) = (metadata.len(), modified.as_secs() as i64);
+
+        if let Some(cached) = self.entries.lock().unwrap().get(path) {
+            if (cached.size, cached.mtime) == (size, mtime) {
+                return Some(cached.timestamp);
+            }
+        }
+
+        let from_mtime = (mtime, modified.subsec_nanos());
+        let timestamp = match source {
+            TimestampSource::Exif => exif_timestamp(path, tz).unwrap_or(from_mtime),
+            _ => from_mtime,
+        };
+        self.insert(path.to_path_buf(), size, mtime, timestamp);
+        Some(timestamp)
+    }
+}
+
+/// The capture time recorded in a frame's EXIF data. Times without an
+/// OffsetTimeOriginal tag are in `tz`.
+fn exif_timestamp(path: &Path, tz: Tz) -> Option<(i64, u32)> {
+    let mut reader = BufReader::new(File::open(path).ok()?);
+    let exif = exif::Reader::new().read_from_container(&mut reader).ok()?;
+    let ascii = |tag| match &exif.get_field(tag, exif::In::PRIMARY)?.value {
+        exif::Value::Ascii(values) => values.first().cloned(),
+        _ => None,
+    };
+
+    let mut time = exif::DateTime::from_ascii(&ascii(exif::Tag::DateTimeOriginal)?).ok()?;
+    if let Some(subsec) = ascii(exif::Tag::SubSecTimeOriginal) {
+        let _ = time.parse_subsec(&subsec);
+    }
+    if let Some(offset) = ascii(exif::Tag::OffsetTimeOriginal) {
+        let _ = time.parse_offset(&offset);
+    }
+
+    let naive = NaiveDate::from_ymd_opt(time.year.into(), time.month.into(), time.day.into())?
+        .and_hms_nano_opt(
+            time.hour.into(),
+            time.minute.into(),
+            time.second.into(),
+            time.nanosecond.unwrap_or(0),
+        )?;
+    let utc = match time.offset {
+        Some(minutes) => Utc.from_utc_datetime(&naive) - chrono::TimeDelta::minutes(minutes.into()),
+        None => tz.from_local_datetime(&naive).earliest()?.to_utc(),
+    };
+    Some((utc.timestamp(), utc.timestamp_subsec_nanos()))
+}
+
+fn parse_formatted(stem: &str, format: &str, tz: Tz) -> Option<(i64, u32)> {
+    let time = match DateTime::parse_from_str(stem, format) {
+        Ok(time) => time.to_utc(),
+        Err(_) => {
+            let naive = NaiveDateTime::parse_from_str(stem, format).ok()?;
+            tz.from_local_datetime(&naive).earliest()?.to_utc()
+        }
+    };
+    Some((time.timestamp(), time.timestamp_subsec_nanos()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn naming(prefix: &str, unit: TimestampUnit, format: Option<&str>) -> FrameNaming {
+        FrameNaming {
+            filename_prefix: prefix.to_string(),
+            timestamp_unit: unit,
+            timestamp_format: format.map(|format| format.parse().unwrap()),
+            timestamp_source: TimestampSource::Filename,
+            timezone: None,
+        }
+    }
+
+    #[test]
+    fn test_epoch_names() {
+        let seconds = FrameNaming::default();
+        assert_eq!(seconds.timestamp("1700000000.jpg"), Some((1700000000, 0)));
+        assert_eq!(
+            seconds.timestamp("1700000000.25.jpg"),
+            Some((1700000000, 250_000_000))
+        );
+        assert_eq!(seconds.timestamp("1700000000.PNG"), Some((1700000000, 0)));
+        assert_eq!(seconds.timestamp("1700000000.webp"), Some((1700000000, 0)));
+        assert_eq!(seconds.timestamp("1700000000.jpeg"), Some((1700000000, 0)));
+        assert_eq!(seconds.timestamp("1700000000"), None);
+        assert_eq!(seconds.timestamp("1700000000.txt"), None);
+        assert_eq!(seconds.timestamp("notes.txt"), None);
+        assert_eq!(seconds.timestamp("17000000x0.jpg"), None);
+
+        let millis = naming("cam1_", TimestampUnit::Ms, None);
+        assert_eq!(
+            millis.timestamp("cam1_1700000000123.jpg"),
+            Some((1700000000, 123_000_000))
+        );
+        assert_eq!(millis.timestamp("cam2_1700000000123.jpg"), None);
+        assert_eq!(millis.timestamp("-1500.jpg"), None);
+        assert_eq!(
+            naming("", TimestampUnit::Ms, None).timestamp("-1500.jpg"),
+            Some((-2, 500_000_000))
+        );
+        assert_eq!(
+            seconds.file_name(1700000000, 250_000_000, "jpg").as_deref(),
+            Some("1700000000.jpg")
+        );
+        assert_eq!(
+            millis.file_name(1700000000, 123_456_789, "jpg").as_deref(),
+            Some("cam1_1700000000123.jpg")
+        );
+        assert_eq!(
+            seconds.range_prefix(1700000000, 1700086400),
+            Some("17000".to_string())
+        );
+        assert_eq!(
+            millis.range_prefix(1700000000, 1700000900),
+            Some("cam1_1700000".to_string())
+        );
+        assert_eq!(seconds.range_prefix(999999999, 1000000001), None);
+    }
+
+    #[test]
+    fn test_formatted_names() {
+        let utc = naming("", TimestampUnit::S, Some("%Y%m%d_%H%M%S%.f%z"));
+        assert_eq!(
+            utc.timestamp("20231114_221320.5+0000.jpg"),
+            Some((1700000000, 500_000_000))
+        );
+
+        let local = FrameNaming {
+            timezone: Some(Tz::America__New_York),
+            ..naming("IMG_", TimestampUnit::S, Some("%Y%m%d_%H%M%S"))
+        };
+        // 22:13:20 EST
+        let expected = 1700018000;
+        assert_eq!(
+            local.timestamp("IMG_20231114_221320.jpg"),
+            Some((expected, 0))
+        );
+        assert_eq!(local.timestamp("IMG_2023.jpg"), None);
+        assert_eq!(
+            local.file_name(expected, 0, "jpg").as_deref(),
+            Some("IMG_20231114_221320.jpg")
+        );
+        assert!("%Y%Q".parse::<TimestampFormat>().is_err());
+    }
+
+    /// A small JPEG, with the given EXIF fields if there are any.
+    fn jpeg_with_exif(fields: &[exif::Field]) -> Vec<u8> {
+        let mut jpeg = std::io::Cursor::new(Vec::new());
+        image::RgbImage::new(8, 8)
+            .write_to(&mut jpeg, image::ImageFormat::Jpeg)
+            .unwrap();
+        let jpeg = jpeg.into_inner();
+        if fields.is_empty() {
+            return jpeg;
+        }
+
+        let mut writer = exif::experimental::Writer::new();
+        for field in fields {
+            writer.push_field(field);
+        }
+        let mut tiff = std::io::Cursor::new(Vec::new());
+        writer.write(&mut tiff, false).unwrap();
+        let tiff = tiff.into_inner();
+
+        let mut contents = vec![0xFF, 0xD8, 0xFF, 0xE1];
+        contents.extend_from_slice(&((tiff.len() + 8) as u16).to_be_bytes());
+        contents.extend_from_slice(b"Exif\0\0");
+        contents.extend_from_slice(&tiff);
+        contents.extend_from_slice(&jpeg[2..]);
+        contents
+    }
+
+    fn ascii(tag: exif::Tag, value: &str) -> exif::Field {
+        exif::Field {
+            tag,
+            ifd_num: exif::In::PRIMARY,
+            value: exif::Value::Ascii(vec![value.as_bytes().to_vec()]),
+        }
+    }
+
+    #[test]
+    fn test_timestamps_from_file_contents() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = TimestampCache::default();
+        let exif = FrameNaming {
+            timestamp_source: TimestampSource::Exif,
+            ..Default::default()
+        };
+
+        let tagged = dir.path().join("IMG_0001.jpg");
+        fs::write(
+            &tagged,
+            jpeg_with_exif(&[
+                ascii(exif::Tag::DateTimeOriginal, "2023:11:15 00:13:20"),
+                ascii(exif::Tag::SubSecTimeOriginal, "5"),
+                ascii(exif::Tag::OffsetTimeOriginal, "+02:00"),
+            ]),
+        )
+        .unwrap();
+        assert_eq!(
+            exif.frame_timestamp(&tagged, &cache),
+            Some((1700000000, 500_000_000))
+        );
+
+        // Frames without EXIF fall back to their modification time
+        let untagged = dir.path().join("IMG_0002.jpg");
+        fs::write(&untagged, jpeg_with_exif(&[])).unwrap();
+        let modified = UNIX_EPOCH + std::time::Duration::from_secs(1_600_000_000);
+        File::options()
+            .write(true)
+            .open(&untagged)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+        assert_eq!(
+            exif.frame_timestamp(&untagged, &cache),
+            Some((1_600_000_000, 0))
+        );
+        let mtime = FrameNaming {
+            timestamp_source: TimestampSource::Mtime,
+            ..Default::default()
+        };
+        assert_eq!(
+            mtime.frame_timestamp(&untagged, &TimestampCache::default()),
+            Some((1_600_000_000, 0))
+        );
+        assert_eq!(
+            exif.frame_timestamp(&dir.path().join("notes.txt"), &cache),
+            None
+        );
+
+        // Cached timestamps are used until the file changes
+        let metadata = fs::metadata(&tagged).unwrap();
+        let mtime = metadata.modified().unwrap().duration_since(UNIX_EPOCH);
+        cache.insert(
+            tagged.clone(),
+            metadata.len(),
+            mtime.unwrap().as_secs() as i64,
+            (42, 0),
+        );
+        assert_eq!(exif.frame_timestamp(&tagged, &cache), Some((42, 0)));
+        fs::write(
+            &tagged,
+            jpeg_with_exif(&[ascii(exif::Tag::DateTimeOriginal, "2023:11:14 22:13:20")]),
+        )
+        .unwrap();
+        assert_eq!(exif.frame_timestamp(&tagged, &cache), Some((1700000000, 0)));
+    }
+}
@@ -0,0 +1,86 @@
+use chrono::{DateTime, Timelike, Utc};
+
+/// Sun elevation at sunrise and sunset, accounting for refraction and the
+/// size of the solar disc.
+pub const SUNRISE_ELEVATION: f64 = -0.833;
+
+/// Sun elevation at the start of morning and end of evening civil twilight.
+pub const CIVIL_TWILIGHT_ELEVATION: f64 = -6.0;
+
+/// Elevation of the sun above the horizon in degrees, using the NOAA solar
+/// position equations. A frame was taken in daylight when this is above
+/// [`SUNRISE_ELEVATION`], which is the same test that defines sunrise and
+/// sunset, so no per-day sunrise table is needed.
+pub fn solar_elevation(latitude: f64, longitude: f64, time: DateTime<Utc>) -> f64 {
+    let julian_day = time.timestamp() as f64 / 86_400.0 + 2_440_587.5;
+    let t = (julian_day - 2_451_545.0) / 36_525.0;
+
+    let mean_longitude = (280.46646 + t * (36000.76983 + t * 0.0003032)).rem_euclid(360.0);
+    let mean_anomaly = 357.52911 + t * (35999.05029 - 0.0001537 * t);
+    let eccentricity = 0.016708634 - t * (0.000042037 + 0.0000001267 * t);
+
+    let m = mean_anomaly.to_radians();
+    let center = m.sin() * (1.914602 - t * (0.004817 + 0.000014 * t))
+        + (2.0 * m).sin() * (0.019993 - 0.000101 * t)
+        + (3.0 * m).sin() * 0.000289;
+    let omega = (125.04 - 1934.136 * t).to_radians();
+    let apparent_longitude =
+        (mean_longitude + center - 0.00569 - 0.00478 * omega.sin()).to_radians();
+
+    let mean_obliquity =
+        23.0 + (26.0 + (21.448 - t * (46.815 + t * (0.00059 - t * 0.001813))) / 60.0) / 60.0;
+    let obliquity = (mean_obliquity + 0.00256 * omega.cos()).to_radians();
+    let declination = (obliquity.sin() * apparent_longitude.sin()).asin();
+
+    let l0 = mean_longitude.to_radians();
+    let y = (obliquity / 2.0).tan().powi(2);
+    let equation_of_time = 4.0
+        * (y * (2.0 * l0).sin() - 2.0 * eccentricity * m.sin()
+            + 4.0 * eccentricity * y * m.sin() * (2.0 * l0).cos()
+            - 0.5 * y * y * (4.0 * l0).sin()
+            - 1.25 * eccentricity * eccentricity * (2.0 * m).sin())
+        .to_degrees();
+
+    let minutes = time.num_seconds_from_midnight() as f64 / 60.0;
+    let true_solar_time = (minutes + equation_of_time + 4.0 * longitude).rem_euclid(1440.0);
+    let hour_angle = (true_solar_time / 4.0 - 180.0).to_radians();
+
+    let latitude = latitude.to_radians();
+    let cos_zenith =
+        latitude.sin() * declination.sin() + latitude.cos() * declination.cos() * hour_angle.cos();
+
+    90.0 - cos_zenith.clamp(-1.0, 1.0).acos().to_degrees()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().into()
+    }
+
+    #[test]
+    fn test_solar_elevation_new_york_solstice() {
+        // Solar noon in New York on the June solstice is roughly 72.6 degrees up
+        let noon = solar_elevation(40.71, -74.01, at("2024-06-21T16:57:00Z"));
+        assert!((noon - 72.6).abs() < 0.5, "noon elevation was {}", noon);
+
+        let midnight = solar_elevation(40.71, -74.01, at("2024-06-21T04:57:00Z"));
+        assert!(midnight < CIVIL_TWILIGHT_ELEVATION);
+
+        // Sunrise that day is at 05:25 EDT
+        let sunrise = solar_elevation(40.71, -74.01, at("2024-06-21T09:25:00Z"));
+        assert!(
+            (sunrise - SUNRISE_ELEVATION).abs() < 0.5,
+            "sunrise elevation was {}",
+            sunrise
+        );
+    }
+
+    #[test]
+    fn test_solar_elevation_polar_night() {
+        let noon = solar_elevation(78.22, 15.65, at("2024-12-21T11:00:00Z"));
+        assert!(noon < CIVIL_TWILIGHT_ELEVATION);
+    }
+}